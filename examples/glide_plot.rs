@@ -54,7 +54,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             mock_input
                 .iter()
                 .enumerate()
-                .map(|(i, x)| (i as f32 / SAMPLE_RATE as f32, *x)),
+                .map(|(i, x)| (i as f32 / SAMPLE_RATE, *x)),
             BLUE,
         ))?
         .label("Raw input")
@@ -73,7 +73,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
 
                 let y = glide.process(*x);
-                (i as f32 / SAMPLE_RATE as f32, y)
+                (i as f32 / SAMPLE_RATE, y)
             }),
             RED,
         ))?
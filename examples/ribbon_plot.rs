@@ -14,7 +14,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ribbon_controller::sample_rate_to_capacity(SAMPLE_RATE as u32);
 
     let mut ribbon = ribbon_controller::RibbonController::<RIBBON_BUFF_CAPACITY>::new(
-        SAMPLE_RATE as f32,
+        SAMPLE_RATE,
         20_000.0, // end-to-end resistance of the softpot, common value for longer softpots. short ones are 10k
         820.0, // resistance of the series resistor going to vref. Value found to work well, feel free to experiment
         1E6,   // pullup resistor from the wiper to the positive voltage refererence
@@ -74,7 +74,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             mock_adc_signal
                 .iter()
                 .enumerate()
-                .map(|(i, x)| (i as f32 / SAMPLE_RATE as f32, *x)),
+                .map(|(i, x)| (i as f32 / SAMPLE_RATE, *x)),
             BLACK,
         ))?
         .label("Raw input")
@@ -85,7 +85,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .draw_series(LineSeries::new(
             mock_adc_signal.iter().enumerate().map(|(i, x)| {
                 ribbon.poll(*x);
-                (i as f32 / SAMPLE_RATE as f32, ribbon.value())
+                (i as f32 / SAMPLE_RATE, ribbon.value())
             }),
             RED,
         ))?
@@ -104,7 +104,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     0.0
                 };
 
-                (i as f32 / SAMPLE_RATE as f32, y)
+                (i as f32 / SAMPLE_RATE, y)
             }),
             BLUE,
         ))?
@@ -256,7 +256,7 @@ pub struct TimePeriod(f32);
 
 impl From<f32> for TimePeriod {
     fn from(p: f32) -> Self {
-        Self(p.max(MIN_TIME_PERIOD_SEC).min(MAX_TIME_PERIOD_SEC))
+        Self(p.clamp(MIN_TIME_PERIOD_SEC, MAX_TIME_PERIOD_SEC))
     }
 }
 
@@ -272,7 +272,7 @@ pub struct SustainLevel(f32);
 
 impl From<f32> for SustainLevel {
     fn from(val: f32) -> Self {
-        Self(val.max(0.0_f32).min(1.0_f32))
+        Self(val.clamp(0.0_f32, 1.0_f32))
     }
 }
 
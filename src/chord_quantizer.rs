@@ -0,0 +1,303 @@
+//! # Chord Quantizer
+//!
+//! A chord quantizer turns a single input into several voices which make up a chord, for paraphonic patches where one
+//! CV plays a whole chord.
+//!
+//! The input is quantized to the root of the chord using a regular `Quantizer`, so all of its settings such as the
+//! scale, tuning, and rounding apply. The other chord tones are found by transposing the root, either by scale degrees
+//! so that the chord stays in the scale, or by steps of the tuning for fixed chord shapes.
+//!
+//! If there are more voices than chord tones, the extra voices double the chord tones in the octaves above. Chords may
+//! be inverted, and spread out into open voicings.
+
+use heapless::Vec;
+
+use crate::quantizer::{Conversion, Quantizer};
+
+/// A chord quantizer with `VOICES` output voices is represented here.
+pub struct ChordQuantizer<const VOICES: usize> {
+    quantizer: Quantizer,
+
+    shape: ChordShape,
+
+    // the number of times the lowest voice is moved up an octave
+    inversion: usize,
+
+    // every other voice is moved up by this many octaves
+    spread: i32,
+}
+
+#[allow(clippy::new_without_default)]
+impl<const VOICES: usize> ChordQuantizer<VOICES> {
+    /// `ChordQuantizer::new()` is a new chord quantizer playing triads, with a new 12-TET quantizer with all notes
+    /// allowed
+    pub fn new() -> Self {
+        Self {
+            quantizer: Quantizer::new(),
+            shape: ChordShape::Triad,
+            inversion: 0,
+            spread: 0,
+        }
+    }
+
+    /// `cq.quantizer()` is the quantizer used to find the root of the chord
+    pub fn quantizer(&self) -> &Quantizer {
+        &self.quantizer
+    }
+
+    /// `cq.quantizer_mut()` is the quantizer used to find the root of the chord, to change its scale and settings
+    pub fn quantizer_mut(&mut self) -> &mut Quantizer {
+        &mut self.quantizer
+    }
+
+    /// `cq.set_shape(s)` sets the chord shape to `s`
+    pub fn set_shape(&mut self, shape: ChordShape) {
+        self.shape = shape;
+    }
+
+    /// `cq.shape()` is the current chord shape
+    pub fn shape(&self) -> &ChordShape {
+        &self.shape
+    }
+
+    /// `cq.set_inversion(n)` sets the chord inversion to `n`, clamped to `[0, VOICES - 1]`
+    ///
+    /// Each inversion moves the lowest voice up an octave, so the first inversion of C E G is E G C.
+    pub fn set_inversion(&mut self, inversion: usize) {
+        self.inversion = inversion.min(VOICES.saturating_sub(1));
+    }
+
+    /// `cq.inversion()` is the current chord inversion
+    pub fn inversion(&self) -> usize {
+        self.inversion
+    }
+
+    /// `cq.set_spread(n)` spreads the chord into an open voicing by moving every other voice up `n` octaves
+    ///
+    /// The second, fourth, and so on voices are moved, counting from the lowest voice after inversion. Zero spread is
+    /// a close voicing.
+    pub fn set_spread(&mut self, octaves: u8) {
+        self.spread = octaves as i32;
+    }
+
+    /// `cq.spread()` is the current spread in octaves
+    pub fn spread(&self) -> u8 {
+        self.spread as u8
+    }
+
+    /// `cq.convert(v)` is the chord for input `v` in volts, as one conversion for each voice
+    ///
+    /// Before spreading, voices are ordered from lowest to highest.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::{chord_quantizer::{ChordQuantizer, ChordShape}, quantizer::{Note, Scale}};
+    /// let mut cq = ChordQuantizer::<3>::new();
+    /// cq.quantizer_mut().set_scale(Scale::Major, Note::C);
+    ///
+    /// // a D minor triad, D F A
+    /// let chord = cq.convert(2.0 / 12.0);
+    /// assert_eq!(chord.map(|c| c.note_num), [2, 5, 9]);
+    ///
+    /// // first inversion, F A D
+    /// cq.set_inversion(1);
+    /// let chord = cq.convert(2.0 / 12.0);
+    /// assert_eq!(chord.map(|c| c.note_num), [5, 9, 14]);
+    /// ```
+    pub fn convert(&mut self, v_in: f32) -> [Conversion; VOICES] {
+        let root = self.quantizer.convert(v_in);
+
+        let mut voices = [root; VOICES];
+        for (i, voice) in voices.iter_mut().enumerate() {
+            *voice = self.chord_tone(root, i);
+        }
+
+        for _ in 0..self.inversion {
+            if let Some(lowest) = voices.iter_mut().min_by_key(|v| v.note_num) {
+                *lowest = self.quantizer.shift_octaves(*lowest, 1);
+            }
+        }
+        voices.sort_unstable_by_key(|v| v.note_num);
+
+        for voice in voices.iter_mut().skip(1).step_by(2) {
+            *voice = self.quantizer.shift_octaves(*voice, self.spread);
+        }
+
+        voices
+    }
+
+    /// `cq.chord_tone(r, i)` is chord tone number `i` above root `r` in close voicing
+    ///
+    /// Chord tones past the end of the shape wrap around to the start of the shape, one octave higher.
+    fn chord_tone(&self, root: Conversion, i: usize) -> Conversion {
+        let (offsets, by_degrees) = self.shape.offsets();
+        if offsets.is_empty() {
+            return root;
+        }
+
+        let offset = offsets[i % offsets.len()] as i32;
+        let tone = if by_degrees {
+            self.quantizer.transpose_degrees(root, offset)
+        } else {
+            self.quantizer.transpose_steps(root, offset)
+        };
+
+        self.quantizer
+            .shift_octaves(tone, (i / offsets.len()) as i32)
+    }
+}
+
+/// Chord shapes are represented here
+///
+/// The built in shapes are made from stacked scale degrees, so they are major, minor, or diminished depending on the
+/// scale and where the root is in it. With all notes allowed the scale degrees are single steps of the tuning.
+///
+/// - `Triad` is the root, third, and fifth, scale degrees 0, 2, and 4
+///
+/// - `Seventh` is a triad plus the seventh, scale degrees 0, 2, 4, and 6
+///
+/// - `Sus2` is the root, second, and fifth, scale degrees 0, 1, and 4
+///
+/// - `Sus4` is the root, fourth, and fifth, scale degrees 0, 3, and 4
+///
+/// - `Intervals` is a custom chord given as steps of the tuning above the root, semitones in 12-TET. The chord tones
+///   may land on forbidden notes.
+///
+/// - `Degrees` is a custom chord given as scale degrees above the root, for example `[0, 3, 6]` is stacked fourths.
+///   The chord tones are always on allowed notes.
+///
+/// Custom chords should list the root first and go from lowest to highest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChordShape {
+    Triad,
+    Seventh,
+    Sus2,
+    Sus4,
+    Intervals(Vec<i8, MAX_CHORD_TONES>),
+    Degrees(Vec<i8, MAX_CHORD_TONES>),
+}
+
+impl ChordShape {
+    /// `ChordShape::from_intervals(is)` is a custom chord shape with intervals `is` in steps of the tuning
+    ///
+    /// Only the first `MAX_CHORD_TONES` intervals are used.
+    pub fn from_intervals(intervals: &[i8]) -> Self {
+        Self::Intervals(intervals.iter().copied().take(MAX_CHORD_TONES).collect())
+    }
+
+    /// `ChordShape::from_degrees(ds)` is a custom chord shape with scale degrees `ds`
+    ///
+    /// Only the first `MAX_CHORD_TONES` degrees are used.
+    pub fn from_degrees(degrees: &[i8]) -> Self {
+        Self::Degrees(degrees.iter().copied().take(MAX_CHORD_TONES).collect())
+    }
+
+    /// `s.offsets()` is the offsets of each chord tone from the root, and true iff they are scale degrees
+    fn offsets(&self) -> (&[i8], bool) {
+        match self {
+            Self::Triad => (&[0, 2, 4], true),
+            Self::Seventh => (&[0, 2, 4, 6], true),
+            Self::Sus2 => (&[0, 1, 4], true),
+            Self::Sus4 => (&[0, 3, 4], true),
+            Self::Intervals(intervals) => (intervals, false),
+            Self::Degrees(degrees) => (degrees, true),
+        }
+    }
+}
+
+/// The most chord tones a custom chord shape may have
+pub const MAX_CHORD_TONES: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantizer::{Note, Scale};
+
+    fn c_major<const VOICES: usize>() -> ChordQuantizer<VOICES> {
+        let mut cq = ChordQuantizer::new();
+        cq.quantizer_mut().set_scale(Scale::Major, Note::C);
+        cq
+    }
+
+    fn note_nums<const VOICES: usize>(chord: [Conversion; VOICES]) -> [i16; VOICES] {
+        chord.map(|c| c.note_num)
+    }
+
+    #[test]
+    fn triads_follow_the_scale() {
+        let mut cq = c_major::<3>();
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 4, 7]);
+        assert_eq!(note_nums(cq.convert(4.0 / 12.0)), [4, 7, 11]);
+        assert_eq!(note_nums(cq.convert(11.0 / 12.0)), [11, 14, 17]);
+    }
+
+    #[test]
+    fn seventh_and_sus_chords() {
+        let mut cq = c_major::<4>();
+        cq.set_shape(ChordShape::Seventh);
+        assert_eq!(note_nums(cq.convert(7.0 / 12.0)), [7, 11, 14, 17]);
+
+        let mut cq = c_major::<3>();
+        cq.set_shape(ChordShape::Sus2);
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 2, 7]);
+        cq.set_shape(ChordShape::Sus4);
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 5, 7]);
+    }
+
+    #[test]
+    fn custom_intervals_ignore_the_scale() {
+        let mut cq = c_major::<3>();
+        // an augmented triad has notes outside of C major
+        cq.set_shape(ChordShape::from_intervals(&[0, 4, 8]));
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 4, 8]);
+    }
+
+    #[test]
+    fn custom_degrees_stay_in_the_scale() {
+        let mut cq = c_major::<3>();
+        cq.set_shape(ChordShape::from_degrees(&[0, 3, 6]));
+        // stacked fourths from C, C F B
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 5, 11]);
+    }
+
+    #[test]
+    fn extra_voices_double_the_chord_an_octave_up() {
+        let mut cq = c_major::<5>();
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 4, 7, 12, 16]);
+    }
+
+    #[test]
+    fn inversions_move_the_lowest_voice_up() {
+        let mut cq = c_major::<3>();
+        cq.set_inversion(2);
+        assert_eq!(note_nums(cq.convert(0.0)), [7, 12, 16]);
+
+        // inversion is clamped to the number of voices
+        cq.set_inversion(10);
+        assert_eq!(cq.inversion(), 2);
+    }
+
+    #[test]
+    fn spread_opens_the_voicing() {
+        let mut cq = c_major::<4>();
+        cq.set_shape(ChordShape::Seventh);
+        cq.set_spread(1);
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 16, 7, 23]);
+    }
+
+    #[test]
+    fn empty_custom_chord_plays_the_root() {
+        let mut cq = c_major::<2>();
+        cq.set_shape(ChordShape::from_intervals(&[]));
+        assert_eq!(note_nums(cq.convert(0.0)), [0, 0]);
+    }
+
+    #[test]
+    fn stairsteps_match_note_nums() {
+        let mut cq = c_major::<3>();
+        for voice in cq.convert(2.0 + 0.1 / 12.0) {
+            assert!((voice.stairstep - voice.note_num as f32 / 12.0).abs() < 0.000_1);
+        }
+    }
+}
@@ -4,75 +4,287 @@
 //! so that new notes slide into each other instead of stepping directly to the new note.
 //!
 //! The terms glide, lag, and portamento are often used interchangeably.
+//!
+//! Rising and falling inputs may be given independent glide times, this allows for asymmetrical slew such as a fast
+//! rise and slow fall, which is useful for things like envelope followers.
+//!
+//! Several glide modes are available:
+//!
+//! - `Exponential` glide is the classic analog RC lag, the output approaches the target asymptotically
+//!
+//! - `ConstantRate` glide slews linearly at a fixed rate, so bigger jumps take longer to arrive
+//!
+//! - `ConstantTime` glide slews linearly and always arrives in the glide time, no matter how big the jump is
 
 use crate::utils::*;
-use biquad::*;
 
 /// A glide processor for implementing portamento is represented here.
 pub struct GlideProcessor {
-    // min and max cutoff frequencies
-    min_fc: f32,
-    max_fc: f32,
-
     // sampe rate in hertz
-    fs: Hertz<f32>,
+    fs: f32,
+
+    mode: GlideMode,
+
+    // the glide times in seconds
+    rise_time: f32,
+    fall_time: f32,
+
+    // one-pole lowpass filter coefficients for rising and falling inputs, used in exponential mode
+    rise_coeff: f32,
+    fall_coeff: f32,
+
+    // the most recent output, used to decide if the input is rising or falling
+    last_output: f32,
+
+    // the value the glide is currently heading towards
+    target: f32,
+
+    // the amount to move per sample in constant-time mode, always positive
+    increment: f32,
 
-    // internal lowpass filter to implement the glide
-    lpf: DirectForm1<f32>,
+    // true while the output is still making its way towards the target
+    gliding: bool,
 
-    // cached val to avoid recalculating unnecessarily
-    cached_t: f32,
+    // true iff the output arrived at the target after gliding, self clearing
+    arrived: bool,
 }
 
 impl GlideProcessor {
     /// `GlideProcessor::new(sr)` is a new glide processor with sample rate `sr`
     pub fn new(sample_rate_hz: f32) -> Self {
-        let max_fc = sample_rate_hz / 2.0_f32;
-
-        let coeffs = coeffs(sample_rate_hz.hz(), max_fc.hz());
-
         Self {
-            max_fc,
-            min_fc: 0.1_f32,
-            fs: sample_rate_hz.hz(),
-            lpf: DirectForm1::<f32>::new(coeffs),
-            cached_t: -1.0_f32, // initialized such that it always updates the first go-round
+            fs: sample_rate_hz,
+            mode: GlideMode::Exponential,
+            rise_time: 0.0_f32,
+            fall_time: 0.0_f32,
+            rise_coeff: 1.0_f32,
+            fall_coeff: 1.0_f32,
+            last_output: 0.0_f32,
+            target: 0.0_f32,
+            increment: 0.0_f32,
+            gliding: false,
+            arrived: false,
         }
     }
 
+    /// `gp.set_mode(m)` sets the glide mode to `m`
+    ///
+    /// The mode may be changed at any time, the output continues on from wherever it currently is.
+    pub fn set_mode(&mut self, mode: GlideMode) {
+        self.mode = mode;
+        // restart any constant-time glide from the current position
+        self.target = self.last_output;
+    }
+
     /// `gp.set_time(t)` sets the portamento time for the glide processor to the new time `t`
     ///
+    /// Both the rise and fall times are set to `t`, see `set_rise_time` and `set_fall_time` for asymmetrical glide.
+    ///
     /// # Arguments:
     ///
     /// * `t` - the new value for the glide control time, in `[0.0, 10.0]`
     ///
-    /// Times that would be faster than sample_rate/2 are clamped.
+    /// In exponential mode the time is roughly how long it takes to get very close to the target. In constant-rate
+    /// mode the time is how long it takes to slew 1 volt (1 octave with 1volt/octave scaling). In constant-time mode
+    /// the time is exactly how long it takes to arrive at the target. Changing the time during a constant-time glide
+    /// speeds up or slows down the rest of the glide, as if the whole glide had been given the new time.
     ///
-    /// This function can be somewhat costly, so don't call it more than necessary
+    /// Times are clamped to `[0.0, 10.0]`.
+    ///
+    /// This function is cheap, so the glide time may be modulated every sample.
     pub fn set_time(&mut self, t: f32) {
-        // don't update the coefficients if you don't need to, it is costly
-        let epsilon = 0.05_f32;
-        if is_almost(t, self.cached_t, epsilon) {
-            return;
-        }
+        self.set_rise_time(t);
+        self.set_fall_time(t);
+    }
 
-        self.cached_t = t;
+    /// `gp.set_rise_time(t)` sets the glide time used when the input is rising to the new time `t`
+    ///
+    /// # Arguments:
+    ///
+    /// * `t` - the new value for the rising glide time, in `[0.0, 10.0]`
+    ///
+    /// Times are clamped to `[0.0, 10.0]`.
+    ///
+    /// This function is cheap, so the glide time may be modulated every sample.
+    pub fn set_rise_time(&mut self, t: f32) {
+        let t = t.clamp(0.0_f32, MAX_TIME_SEC);
+        self.rescale_glide(self.rise_time, t, true);
+        self.rise_time = t;
+        self.rise_coeff = self.time_to_coeff(self.rise_time);
+    }
 
-        let f0 = (1.0_f32 / t).max(self.min_fc).min(self.max_fc);
-        self.lpf.update_coefficients(coeffs(self.fs, f0.hz()))
+    /// `gp.set_fall_time(t)` sets the glide time used when the input is falling to the new time `t`
+    ///
+    /// # Arguments:
+    ///
+    /// * `t` - the new value for the falling glide time, in `[0.0, 10.0]`
+    ///
+    /// Times are clamped to `[0.0, 10.0]`.
+    ///
+    /// This function is cheap, so the glide time may be modulated every sample.
+    pub fn set_fall_time(&mut self, t: f32) {
+        let t = t.clamp(0.0_f32, MAX_TIME_SEC);
+        self.rescale_glide(self.fall_time, t, false);
+        self.fall_time = t;
+        self.fall_coeff = self.time_to_coeff(self.fall_time);
     }
 
     /// `gp.process(v)` is the value `v` processed by the glide processor, must be called periodically at the sample rate
     pub fn process(&mut self, val: f32) -> f32 {
-        self.lpf.run(val)
+        let rising = self.last_output < val;
+
+        self.last_output = match self.mode {
+            GlideMode::Exponential => {
+                let coeff = if rising {
+                    self.rise_coeff
+                } else {
+                    self.fall_coeff
+                };
+                self.last_output + (val - self.last_output) * coeff
+            }
+            GlideMode::ConstantRate => {
+                let t = self.time(rising);
+                let max_step = 1.0_f32 / (t * self.fs);
+                self.last_output + (val - self.last_output).clamp(-max_step, max_step)
+            }
+            GlideMode::ConstantTime => {
+                // a new target restarts the glide so that it arrives in exactly the glide time
+                if val != self.target {
+                    self.increment =
+                        fabs(val - self.last_output) / self.time_to_samples(self.time(rising));
+                }
+                let delta = val - self.last_output;
+                self.last_output + delta.clamp(-self.increment, self.increment)
+            }
+        };
+
+        self.target = val;
+
+        let was_gliding = self.gliding;
+        self.gliding = !self.is_near_target(ARRIVAL_TOLERANCE);
+        if was_gliding && !self.gliding {
+            self.arrived = true;
+        }
+
+        self.last_output
+    }
+
+    /// `gp.value()` is the current output of the glide processor, without processing a new sample
+    pub fn value(&self) -> f32 {
+        self.last_output
+    }
+
+    /// `gp.target()` is the value the glide processor is currently heading towards
+    ///
+    /// This is the most recent value given to `process`
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// `gp.is_near_target(e)` is true iff the current output is within `e` of the target
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::glide_processor::GlideProcessor;
+    /// let mut glide = GlideProcessor::new(1_000.0);
+    /// glide.set_time(1.0);
+    /// glide.process(1.0);
+    ///
+    /// // light up the "gliding" LED
+    /// assert!(!glide.is_near_target(0.01));
+    /// ```
+    pub fn is_near_target(&self, tolerance: f32) -> bool {
+        is_almost(self.last_output, self.target, tolerance)
+    }
+
+    /// `gp.arrived()` is true iff the output has arrived at the target after gliding towards it. Self clearing.
+    ///
+    /// In exponential mode the output never quite reaches the target, so arriving means getting very close to it.
+    pub fn arrived(&mut self) -> bool {
+        if self.arrived {
+            self.arrived = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// `gp.reset_to(v)` immediately jumps the output and target to `v` without gliding
+    ///
+    /// Jumping does not count as arriving.
+    pub fn reset_to(&mut self, val: f32) {
+        self.last_output = val;
+        self.target = val;
+        self.gliding = false;
+        self.arrived = false;
+    }
+
+    /// `gp.time(r)` is the rise time if `r` is true, else the fall time
+    fn time(&self, rising: bool) -> f32 {
+        if rising {
+            self.rise_time
+        } else {
+            self.fall_time
+        }
+    }
+
+    /// `gp.rescale_glide(old, new, r)` adjusts a constant-time glide for its time changing from `old` to `new`
+    ///
+    /// Only a glide in the direction of the changed time is adjusted, rising if `r` is true, else falling. The part of
+    /// the glide which is left keeps the same share of the glide time, so the remaining distance is covered in that
+    /// share of the new time.
+    fn rescale_glide(&mut self, old_time: f32, new_time: f32, rising: bool) {
+        let glide_is_rising = self.last_output < self.target;
+        if self.mode == GlideMode::ConstantTime
+            && self.gliding
+            && glide_is_rising == rising
+            && old_time != new_time
+        {
+            self.increment *= self.time_to_samples(old_time) / self.time_to_samples(new_time);
+        }
+    }
+
+    /// `gp.time_to_samples(t)` is glide time `t` in samples, at least one sample
+    fn time_to_samples(&self, t: f32) -> f32 {
+        (t * self.fs).max(1.0_f32)
+    }
+
+    /// `gp.time_to_coeff(t)` is the one-pole lowpass coefficient for glide time `t`
+    ///
+    /// The cutoff frequency is the reciprocal of the glide time, and the coefficient is the backward-Euler
+    /// approximation `w / (1 + w)` where `w = 2pi * fc / fs`, rearranged so that it needs only one division and a time
+    /// of zero gives a coefficient of exactly 1 (no glide at all).
+    fn time_to_coeff(&self, t: f32) -> f32 {
+        TWO_PI / (t * self.fs + TWO_PI)
     }
 }
 
-/// `coeffs(fs, f0)` is the lowpass filter coefficients for sample rate `fs`, cutoff frequency `f0`, and Q = 0
-fn coeffs(fs: Hertz<f32>, f0: Hertz<f32>) -> Coefficients<f32> {
-    Coefficients::<f32>::from_params(Type::SinglePoleLowPass, fs, f0, 0.0_f32).unwrap()
+/// Glide modes are represented here
+///
+/// - `Exponential` mode is like an analog RC lag, the output approaches the target quickly at first and then slows
+///   down as it gets close. This is the default mode.
+///
+/// - `ConstantRate` mode slews linearly at a fixed number of volts per second, bigger jumps take longer to arrive.
+///   This is how the TB-303 and many analog "linear" lag processors behave.
+///
+/// - `ConstantTime` mode slews linearly and arrives at the target in exactly the glide time regardless of the size of
+///   the jump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GlideMode {
+    Exponential,
+    ConstantRate,
+    ConstantTime,
 }
 
+/// The longest allowed glide time in seconds
+pub const MAX_TIME_SEC: f32 = 10.0_f32;
+
+/// The output is considered to have arrived when it is at least this close to the target
+const ARRIVAL_TOLERANCE: f32 = 0.0001_f32;
+
+const TWO_PI: f32 = 2.0_f32 * core::f32::consts::PI;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +318,246 @@ mod tests {
             last_res = res;
         }
     }
+
+    #[test]
+    fn slowly_turning_the_time_knob_changes_the_output_smoothly() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_time(1.0);
+
+        let mut last_res = glide.process(1.0);
+        let mut last_step = last_res;
+
+        // nudge the time a tiny bit every sample, the steps in the output should change only a tiny bit too
+        for i in 0..500 {
+            glide.set_time(1.0 - i as f32 * 0.0001);
+            let res = glide.process(1.0);
+            let step = res - last_res;
+            assert!(is_almost(step, last_step, 0.0001));
+            last_res = res;
+            last_step = step;
+        }
+    }
+
+    #[test]
+    fn fast_rise_slow_fall() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_rise_time(0.01);
+        glide.set_fall_time(1.0);
+
+        // the fast rise gets to the top quickly
+        for _ in 0..100 {
+            glide.process(1.0);
+        }
+        assert!(is_almost(glide.process(1.0), 1.0, 0.005));
+
+        // the slow fall is still far from the bottom after the same amount of time
+        for _ in 0..100 {
+            glide.process(0.0);
+        }
+        assert!(0.25 < glide.process(0.0));
+    }
+
+    #[test]
+    fn slow_rise_fast_fall() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_rise_time(1.0);
+        glide.set_fall_time(0.01);
+
+        for _ in 0..100 {
+            glide.process(1.0);
+        }
+        let peak = glide.process(1.0);
+        assert!(peak < 0.75);
+
+        // the fast fall gets back down quickly
+        for _ in 0..100 {
+            glide.process(0.0);
+        }
+        assert!(is_almost(glide.process(0.0), 0.0, 0.005));
+    }
+
+    #[test]
+    fn constant_time_arrives_exactly_in_t_for_any_jump() {
+        for jump in [0.1, 1.0, 5.0] {
+            let mut glide = GlideProcessor::new(1_000.0);
+            glide.set_mode(GlideMode::ConstantTime);
+            glide.set_time(0.5);
+
+            // not there yet one sample before the glide time is up
+            for _ in 0..499 {
+                assert!(glide.process(jump) < jump);
+            }
+            // but arrives right on time
+            assert!(is_almost(glide.process(jump), jump, 0.0001));
+        }
+    }
+
+    #[test]
+    fn constant_rate_takes_longer_for_bigger_jumps() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_mode(GlideMode::ConstantRate);
+        // 1 volt per 100 milliseconds
+        glide.set_time(0.1);
+
+        for _ in 0..100 {
+            glide.process(2.0);
+        }
+        // only half way there after 100 milliseconds
+        assert!(is_almost(glide.process(2.0), 1.01, 0.0001));
+
+        for _ in 0..99 {
+            glide.process(2.0);
+        }
+        assert!(is_almost(glide.process(2.0), 2.0, 0.0001));
+    }
+
+    #[test]
+    fn constant_rate_is_linear() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_mode(GlideMode::ConstantRate);
+        glide.set_time(1.0);
+
+        let mut last_res = glide.process(1.0);
+        for _ in 0..100 {
+            let res = glide.process(1.0);
+            assert!(is_almost(res - last_res, 0.001, 0.00001));
+            last_res = res;
+        }
+    }
+
+    #[test]
+    fn zero_time_is_instant_in_every_mode() {
+        for mode in [
+            GlideMode::Exponential,
+            GlideMode::ConstantRate,
+            GlideMode::ConstantTime,
+        ] {
+            let mut glide = GlideProcessor::new(1_000.0);
+            glide.set_mode(mode);
+            glide.set_time(0.0);
+            glide.process(1.0);
+            assert!(is_almost(glide.process(1.0), 1.0, 0.005));
+        }
+    }
+
+    #[test]
+    fn changing_modes_mid_glide_does_not_jump() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_time(1.0);
+
+        let mut last_res = 0.0;
+        for _ in 0..100 {
+            last_res = glide.process(1.0);
+        }
+
+        glide.set_mode(GlideMode::ConstantTime);
+        let res = glide.process(1.0);
+        assert!(last_res < res && is_almost(res, last_res, 0.01));
+
+        glide.set_mode(GlideMode::Exponential);
+        let next_res = glide.process(1.0);
+        assert!(res < next_res && is_almost(res, next_res, 0.01));
+    }
+
+    #[test]
+    fn value_and_target_do_not_process() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_time(1.0);
+
+        let res = glide.process(1.0);
+        assert_eq!(glide.value(), res);
+        assert_eq!(glide.value(), res);
+        assert_eq!(glide.target(), 1.0);
+    }
+
+    #[test]
+    fn arrived_is_raised_once_when_the_glide_finishes() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_mode(GlideMode::ConstantTime);
+        glide.set_time(0.1);
+
+        for _ in 0..99 {
+            glide.process(1.0);
+            assert!(!glide.arrived());
+        }
+
+        glide.process(1.0);
+        assert!(glide.arrived());
+        // self clearing
+        assert!(!glide.arrived());
+
+        // sitting at the target doesn't arrive again
+        glide.process(1.0);
+        assert!(!glide.arrived());
+    }
+
+    #[test]
+    fn constant_time_glide_follows_a_time_change_midway() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_mode(GlideMode::ConstantTime);
+        glide.set_time(1.0);
+
+        for _ in 0..500 {
+            glide.process(1.0);
+        }
+        assert!(is_almost(glide.value(), 0.5, 0.001));
+
+        // the second half of the glide now takes half of the new time
+        for _ in 0..240 {
+            glide.set_time(0.5);
+            glide.process(1.0);
+        }
+        assert!(!glide.is_near_target(0.005));
+
+        for _ in 0..10 {
+            glide.set_time(0.5);
+            glide.process(1.0);
+        }
+        assert!(glide.is_near_target(0.001));
+    }
+
+    #[test]
+    fn exponential_glide_arrives_eventually() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_time(0.1);
+
+        let mut arrived = false;
+        for _ in 0..1_000 {
+            glide.process(1.0);
+            arrived |= glide.arrived();
+        }
+        assert!(arrived);
+    }
+
+    #[test]
+    fn is_near_target_while_gliding() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_mode(GlideMode::ConstantTime);
+        glide.set_time(0.1);
+
+        glide.process(1.0);
+        assert!(!glide.is_near_target(0.5));
+
+        for _ in 0..60 {
+            glide.process(1.0);
+        }
+        assert!(glide.is_near_target(0.5));
+        assert!(!glide.is_near_target(0.1));
+    }
+
+    #[test]
+    fn reset_to_jumps_without_arriving() {
+        let mut glide = GlideProcessor::new(1_000.0);
+        glide.set_time(1.0);
+        glide.process(1.0);
+
+        glide.reset_to(3.0);
+        assert_eq!(glide.value(), 3.0);
+        assert_eq!(glide.target(), 3.0);
+        assert!(glide.is_near_target(0.0));
+        assert!(!glide.arrived());
+
+        // holding the same input stays put
+        assert_eq!(glide.process(3.0), 3.0);
+    }
 }
@@ -0,0 +1,313 @@
+//! Keyboard Split
+//!
+//! A keyboard split feeds one MIDI stream to two monophonic MIDI receivers, one for each zone of the keyboard, for
+//! dual-mono instruments.
+//!
+//! In split mode the keyboard is divided at the split point, notes below it go to the lower zone and the rest go to
+//! the upper zone. In layer mode both zones play every note. Each zone may be transposed on its own, and each zone is a
+//! full `MonoMidiReceiver` with its own note priority, retrigger mode, and gates.
+//!
+//! Messages which are not about a particular note, such as pitch bend and CCs, go to both zones.
+
+use midi_convert::{
+    midi_types::{MidiMessage, Note},
+    MidiByteStreamParser,
+};
+
+use crate::mono_midi_receiver::MonoMidiReceiver;
+
+/// A keyboard split feeding two monophonic MIDI receivers is represented here
+pub struct KeyboardSplit {
+    parser: MidiByteStreamParser,
+
+    lower: MonoMidiReceiver,
+    upper: MonoMidiReceiver,
+
+    mode: ZoneMode,
+
+    // the lowest note of the upper zone in split mode
+    split_point: u8,
+
+    // in semitones
+    lower_transpose: i8,
+    upper_transpose: i8,
+}
+
+impl KeyboardSplit {
+    /// `KeyboardSplit::new(c)` is a new keyboard split where both zones accept messages on MIDI channel `c`
+    ///
+    /// The keyboard is split at middle C, note 60, which is the lowest note of the upper zone.
+    pub fn new(channel: u8) -> Self {
+        Self {
+            parser: MidiByteStreamParser::new(),
+            lower: MonoMidiReceiver::new(channel),
+            upper: MonoMidiReceiver::new(channel),
+            mode: ZoneMode::Split,
+            split_point: DEFAULT_SPLIT_POINT,
+            lower_transpose: 0,
+            upper_transpose: 0,
+        }
+    }
+
+    /// `ks.parse(b)` parses incoming MIDI data in the form of sequential bytes `b` and passes it on to the zones
+    ///
+    /// It is expected to call this function every time a new MIDI byte is received. The bytes must not also be passed
+    /// to the zones' own `parse()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::keyboard_split::{KeyboardSplit, Zone};
+    ///
+    /// let mut ks = KeyboardSplit::new(0);
+    /// ks.set_split_point(60);
+    /// for b in [0x90, 48, 127, 72, 127] {
+    ///     ks.parse(b);
+    /// }
+    ///
+    /// assert_eq!(ks.zone(Zone::Lower).note_num(), 48);
+    /// assert_eq!(ks.zone(Zone::Upper).note_num(), 72);
+    /// ```
+    pub fn parse(&mut self, byte: u8) {
+        let message = match self.parser.parse(byte) {
+            Some(message) => message,
+            None => return,
+        };
+
+        let (to_lower, to_upper) = match (self.mode, note_of(message)) {
+            (ZoneMode::Split, Some(note)) => (note < self.split_point, self.split_point <= note),
+            _ => (true, true),
+        };
+
+        if to_lower {
+            if let Some(m) = transposed(message, self.lower_transpose) {
+                self.lower.handle_message(m);
+            }
+        }
+        if to_upper {
+            if let Some(m) = transposed(message, self.upper_transpose) {
+                self.upper.handle_message(m);
+            }
+        }
+    }
+
+    /// `ks.zone(z)` is the MIDI receiver for zone `z`
+    pub fn zone(&self, zone: Zone) -> &MonoMidiReceiver {
+        match zone {
+            Zone::Lower => &self.lower,
+            Zone::Upper => &self.upper,
+        }
+    }
+
+    /// `ks.zone_mut(z)` is the MIDI receiver for zone `z`, to change its settings and read its self clearing gates
+    pub fn zone_mut(&mut self, zone: Zone) -> &mut MonoMidiReceiver {
+        match zone {
+            Zone::Lower => &mut self.lower,
+            Zone::Upper => &mut self.upper,
+        }
+    }
+
+    /// `ks.set_mode(m)` sets the zone mode to `m`
+    ///
+    /// All notes are turned off, since their note-off messages might go to a different zone than their note-on.
+    pub fn set_mode(&mut self, mode: ZoneMode) {
+        self.mode = mode;
+        self.all_notes_off();
+    }
+
+    /// `ks.mode()` is the current zone mode
+    pub fn mode(&self) -> ZoneMode {
+        self.mode
+    }
+
+    /// `ks.set_split_point(n)` sets the lowest note of the upper zone to `n`, clamped to `[0..127]`
+    ///
+    /// All notes are turned off, since their note-off messages might go to a different zone than their note-on.
+    pub fn set_split_point(&mut self, note: u8) {
+        self.split_point = note.min(127);
+        self.all_notes_off();
+    }
+
+    /// `ks.split_point()` is the lowest note of the upper zone
+    pub fn split_point(&self) -> u8 {
+        self.split_point
+    }
+
+    /// `ks.set_transpose(z, n)` transposes zone `z` by `n` semitones
+    ///
+    /// Notes which are transposed out of the MIDI note range are dropped. The split point is compared to the notes
+    /// before they are transposed. All notes in the zone are turned off, since their note-off messages would no longer
+    /// match.
+    pub fn set_transpose(&mut self, zone: Zone, semitones: i8) {
+        match zone {
+            Zone::Lower => self.lower_transpose = semitones,
+            Zone::Upper => self.upper_transpose = semitones,
+        }
+        self.zone_mut(zone).all_notes_off();
+    }
+
+    /// `ks.transpose(z)` is the transposition of zone `z` in semitones
+    pub fn transpose(&self, zone: Zone) -> i8 {
+        match zone {
+            Zone::Lower => self.lower_transpose,
+            Zone::Upper => self.upper_transpose,
+        }
+    }
+
+    /// `ks.all_notes_off()` turns off the notes in both zones
+    fn all_notes_off(&mut self) {
+        self.lower.all_notes_off();
+        self.upper.all_notes_off();
+    }
+}
+
+/// Keyboard zones are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    Lower,
+    Upper,
+}
+
+/// Zone modes are represented here
+///
+/// - `Split` sends notes below the split point to the lower zone, and the rest to the upper zone
+///
+/// - `Layer` sends every note to both zones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZoneMode {
+    Split,
+    Layer,
+}
+
+/// `note_of(m)` is the note number of MIDI message `m`, if it is about a particular note
+fn note_of(message: MidiMessage) -> Option<u8> {
+    match message {
+        MidiMessage::NoteOn(_, note, _)
+        | MidiMessage::NoteOff(_, note, _)
+        | MidiMessage::KeyPressure(_, note, _) => Some(note.into()),
+        _ => None,
+    }
+}
+
+/// `transposed(m, n)` is MIDI message `m` with its note transposed by `n` semitones
+///
+/// Messages which are not about a particular note are unchanged, and notes out of the MIDI note range are `None`.
+fn transposed(message: MidiMessage, semitones: i8) -> Option<MidiMessage> {
+    let shift = |note: Note| {
+        let shifted = u8::from(note) as i16 + semitones as i16;
+        if (0..=127).contains(&shifted) {
+            Some(Note::from(shifted as u8))
+        } else {
+            None
+        }
+    };
+
+    match message {
+        MidiMessage::NoteOn(ch, note, vel) => shift(note).map(|n| MidiMessage::NoteOn(ch, n, vel)),
+        MidiMessage::NoteOff(ch, note, vel) => {
+            shift(note).map(|n| MidiMessage::NoteOff(ch, n, vel))
+        }
+        MidiMessage::KeyPressure(ch, note, val) => {
+            shift(note).map(|n| MidiMessage::KeyPressure(ch, n, val))
+        }
+        _ => Some(message),
+    }
+}
+
+const DEFAULT_SPLIT_POINT: u8 = 60;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(ks: &mut KeyboardSplit, bytes: &[u8]) {
+        bytes.iter().for_each(|b| ks.parse(*b));
+    }
+
+    #[test]
+    fn split_sends_notes_to_their_zone() {
+        let mut ks = KeyboardSplit::new(0);
+        parse_all(&mut ks, &[0x90, 59, 127]);
+        assert!(ks.zone(Zone::Lower).gate());
+        assert!(!ks.zone(Zone::Upper).gate());
+
+        parse_all(&mut ks, &[0x90, 60, 127]);
+        assert!(ks.zone(Zone::Upper).gate());
+        assert_eq!(ks.zone(Zone::Lower).note_num(), 59);
+
+        parse_all(&mut ks, &[0x80, 59, 0]);
+        assert!(!ks.zone(Zone::Lower).gate());
+        assert!(ks.zone(Zone::Upper).gate());
+    }
+
+    #[test]
+    fn zones_have_their_own_note_priority() {
+        use crate::mono_midi_receiver::NotePriority;
+
+        let mut ks = KeyboardSplit::new(0);
+        ks.zone_mut(Zone::Lower)
+            .set_note_priority(NotePriority::Low);
+        ks.zone_mut(Zone::Upper)
+            .set_note_priority(NotePriority::High);
+        parse_all(&mut ks, &[0x90, 40, 127, 50, 127, 70, 127, 80, 127]);
+        parse_all(&mut ks, &[0x90, 45, 127, 75, 127]);
+        assert_eq!(ks.zone(Zone::Lower).note_num(), 40);
+        assert_eq!(ks.zone(Zone::Upper).note_num(), 80);
+    }
+
+    #[test]
+    fn layer_plays_both_zones_with_their_own_transposition() {
+        let mut ks = KeyboardSplit::new(0);
+        ks.set_mode(ZoneMode::Layer);
+        ks.set_transpose(Zone::Upper, 7);
+        ks.set_transpose(Zone::Lower, -12);
+        parse_all(&mut ks, &[0x90, 60, 127]);
+        assert_eq!(ks.zone(Zone::Lower).note_num(), 48);
+        assert_eq!(ks.zone(Zone::Upper).note_num(), 67);
+
+        parse_all(&mut ks, &[0x80, 60, 0]);
+        assert!(!ks.zone(Zone::Lower).gate());
+        assert!(!ks.zone(Zone::Upper).gate());
+    }
+
+    #[test]
+    fn controllers_go_to_both_zones() {
+        let mut ks = KeyboardSplit::new(0);
+        parse_all(&mut ks, &[0xB0, 0x01, 127, 0xE0, 0x7F, 0x7F]);
+        for zone in [Zone::Lower, Zone::Upper] {
+            assert_eq!(ks.zone(zone).mod_wheel(), 1.0);
+            assert!(0.99 < ks.zone(zone).pitch_bend());
+        }
+    }
+
+    #[test]
+    fn notes_transposed_out_of_range_are_dropped() {
+        let mut ks = KeyboardSplit::new(0);
+        ks.set_transpose(Zone::Upper, 10);
+        parse_all(&mut ks, &[0x90, 120, 127]);
+        assert!(!ks.zone(Zone::Upper).gate());
+    }
+
+    #[test]
+    fn moving_the_split_point_turns_notes_off() {
+        let mut ks = KeyboardSplit::new(0);
+        parse_all(&mut ks, &[0x90, 50, 127]);
+        ks.set_split_point(40);
+        assert!(!ks.zone(Zone::Lower).gate());
+        assert_eq!(ks.split_point(), 40);
+
+        // the note-off now goes to the upper zone, which must not think a note was released
+        parse_all(&mut ks, &[0x80, 50, 0]);
+        assert!(!ks.zone_mut(Zone::Upper).falling_gate());
+    }
+
+    #[test]
+    fn zones_may_listen_to_different_channels() {
+        let mut ks = KeyboardSplit::new(0);
+        ks.set_mode(ZoneMode::Layer);
+        ks.zone_mut(Zone::Upper).set_channel_mask(1 << 1);
+        parse_all(&mut ks, &[0x91, 60, 127]);
+        assert!(!ks.zone(Zone::Lower).gate());
+        assert!(ks.zone(Zone::Upper).gate());
+    }
+}
@@ -2,11 +2,20 @@
 #![doc = include_str!("../README.md")]
 
 pub mod adsr;
+pub mod chord_quantizer;
 pub mod glide_processor;
+pub mod keyboard_split;
 pub mod lfo;
 mod lookup_tables;
+mod midi_channel;
+pub mod midi_clock;
+pub mod midi_encoder;
 pub mod mono_midi_receiver;
 mod phase_accumulator;
+pub mod poly_midi_receiver;
+pub mod portamento;
 pub mod quantizer;
 pub mod ribbon_controller;
+pub mod scala;
+pub mod tuning;
 mod utils;
@@ -0,0 +1,957 @@
+//! MIDI channel state shared by the MIDI receivers
+//!
+//! A MIDI channel parses the incoming byte stream, keeps track of the controllers such as pitch bend and the mod
+//! wheel, and handles the sustain and sostenuto pedals. Note events are passed on to the receiver, which decides what
+//! to do with them based on how many voices it has.
+//!
+//! While a pedal is holding a note, the note-off for it is held back until the pedal is released.
+//!
+//! CCs 0 to 31 are paired with CCs 32 to 63 for 14-bit resolution, the MSB is sent first and then the LSB. Parameter
+//! numbers (RPNs and NRPNs) are selected with their own CCs and then set with data entry, increment, and decrement.
+//!
+//! The VCF cutoff CC has no LSB, so a parameter number may also be chosen to set the VCF cutoff with 14-bit resolution.
+
+use midi_convert::{
+    midi_types::{MidiMessage, Value7},
+    MidiByteStreamParser,
+};
+
+/// The state of one MIDI channel is represented here.
+pub(crate) struct MidiChannel {
+    parser: MidiByteStreamParser,
+
+    // the MIDI channels to listen to, one bit per channel
+    channel_mask: u16,
+
+    // respond to every channel, regardless of the channel mask
+    omni: bool,
+
+    // the channel mode requested by the controller, mono if true, else poly
+    mono_mode: bool,
+
+    local_control: bool,
+
+    // in `[-1.0, 1.0]`
+    pitch_bend: f32,
+
+    // channel pressure, and the key pressure of each note, in `[0..127]`
+    channel_pressure: u8,
+    key_pressures: [u8; 128],
+
+    // how far a full pitch bend up and down goes, in semitones
+    bend_range_up: f32,
+    bend_range_down: f32,
+
+    // the parameter selected for data entry, `None` if no parameter is selected
+    selected_parameter: Option<Parameter>,
+
+    portamento_enabled: bool,
+    sustain_enabled: bool,
+    sostenuto_enabled: bool,
+
+    // the notes whose keys are physically held down, one bit per MIDI note
+    held_notes: u128,
+
+    // notes which were held down when the sostenuto pedal was pressed
+    sostenuto_notes: u128,
+
+    // notes which have been released but are still sounding because of the pedals
+    released_notes: u128,
+
+    // the last value received for every CC number
+    cc_values: [u8; 128],
+
+    // the MSB CCs in `[0..31]` whose LSB has been received since the MSB, one bit per CC
+    fine_ccs: u32,
+
+    // the CC number assigned to each CC slot, if any
+    cc_map: [Option<u8>; MAX_CC_SLOTS],
+
+    // the slot which the next learnable CC will be assigned to, if MIDI learn is active
+    learning_slot: Option<usize>,
+
+    // the parameter assigned to each parameter slot, if any, and its 14-bit value
+    parameter_map: [Option<Parameter>; MAX_PARAMETER_SLOTS],
+    parameter_values: [u16; MAX_PARAMETER_SLOTS],
+
+    // the parameter which also sets the VCF cutoff, if any
+    vcf_cutoff_parameter: Option<Parameter>,
+
+    // 14-bit, set by either the VCF cutoff CC or the VCF cutoff parameter, whichever was received last
+    vcf_cutoff: u16,
+}
+
+/// MIDI parameter numbers are represented here
+///
+/// - `Registered` is a registered parameter number (RPN), whose meaning is defined by the MIDI specification
+///
+/// - `NonRegistered` is a non-registered parameter number (NRPN), whose meaning is up to the manufacturer
+///
+/// Parameter numbers are 14-bit, in `[0, 16383]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parameter {
+    Registered(u16),
+    NonRegistered(u16),
+}
+
+/// Note events passed from a MIDI channel to a receiver are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ChannelEvent {
+    /// A note was played with velocity in `[0.0, 1.0]`
+    NoteOn(u8, f32),
+    /// A note was released
+    NoteOff(u8),
+    /// The pedals let go of all of these notes at once, one bit per MIDI note
+    ReleaseNotes(u128),
+    /// Every note should stop immediately
+    AllNotesOff,
+}
+
+impl MidiChannel {
+    /// `MidiChannel::new(c)` is a new MIDI channel which accepts messages on channel `c`, clamped to `[0..15]`
+    pub(crate) fn new(channel: u8) -> Self {
+        Self {
+            parser: MidiByteStreamParser::new(),
+
+            channel_mask: 1 << channel.min(15),
+            omni: false,
+            mono_mode: false,
+            local_control: true,
+
+            pitch_bend: 0.0_f32,
+            channel_pressure: 0,
+            key_pressures: [0; 128],
+
+            bend_range_up: DEFAULT_PITCH_BEND_RANGE,
+            bend_range_down: DEFAULT_PITCH_BEND_RANGE,
+            selected_parameter: None,
+
+            portamento_enabled: true,
+            sustain_enabled: false,
+            sostenuto_enabled: false,
+
+            held_notes: 0,
+            sostenuto_notes: 0,
+            released_notes: 0,
+
+            cc_values: [0; 128],
+            fine_ccs: 0,
+            cc_map: [None; MAX_CC_SLOTS],
+            learning_slot: None,
+
+            parameter_map: [None; MAX_PARAMETER_SLOTS],
+            parameter_values: [0; MAX_PARAMETER_SLOTS],
+
+            vcf_cutoff_parameter: None,
+            vcf_cutoff: 0,
+        }
+    }
+
+    /// `ch.parse(b)` parses MIDI byte `b`, updates the controllers, and is the resulting note event if there is one
+    pub(crate) fn parse(&mut self, byte: u8) -> Option<ChannelEvent> {
+        let message = self.parser.parse(byte)?;
+        self.handle_message(message)
+    }
+
+    /// `ch.handle_message(m)` updates the controllers from MIDI message `m`, and is the resulting note event if any
+    pub(crate) fn handle_message(&mut self, message: MidiMessage) -> Option<ChannelEvent> {
+        match message {
+            MidiMessage::NoteOn(ch, note, vel) if self.listens_to(ch.into()) => {
+                // note-on with velocity of zero is interpreted as note-off
+                if 0 == u8::from(vel) {
+                    self.handle_note_off(note.into())
+                } else {
+                    self.handle_note_on(note.into(), vel)
+                }
+            }
+            MidiMessage::NoteOff(ch, note, _) if self.listens_to(ch.into()) => {
+                self.handle_note_off(note.into())
+            }
+            MidiMessage::PitchBendChange(ch, val_u14) if self.listens_to(ch.into()) => {
+                self.pitch_bend = f32::from(val_u14);
+                None
+            }
+            MidiMessage::ChannelPressure(ch, val7) if self.listens_to(ch.into()) => {
+                self.channel_pressure = val7.into();
+                None
+            }
+            MidiMessage::KeyPressure(ch, note, val7) if self.listens_to(ch.into()) => {
+                self.key_pressures[u8::from(note) as usize] = val7.into();
+                None
+            }
+            MidiMessage::ControlChange(ch, cc, val7) if self.listens_to(ch.into()) => {
+                self.handle_control_change(cc.into(), val7)
+            }
+            _ => None, // ignore all other MIDI messages
+        }
+    }
+
+    fn handle_note_on(&mut self, note: u8, velocity: Value7) -> Option<ChannelEvent> {
+        self.held_notes |= note_bit(note);
+        // each key press starts with no pressure
+        self.key_pressures[note as usize] = 0;
+        // a note played again while the pedals hold it is a fresh note, it is no longer waiting to be released
+        self.released_notes &= !note_bit(note);
+        Some(ChannelEvent::NoteOn(note, value7_to_f32(velocity)))
+    }
+
+    fn handle_note_off(&mut self, note: u8) -> Option<ChannelEvent> {
+        let was_held = self.held_notes & note_bit(note) != 0;
+        self.held_notes &= !note_bit(note);
+
+        if !was_held {
+            // a note-off for a note which was never played, or a repeated note-off for a note the pedals are holding
+            None
+        } else if self.is_held_by_pedal(note) {
+            self.released_notes |= note_bit(note);
+            None
+        } else {
+            Some(ChannelEvent::NoteOff(note))
+        }
+    }
+
+    fn handle_control_change(&mut self, cc: u8, val7: Value7) -> Option<ChannelEvent> {
+        let value = u8::from(val7);
+        self.cc_values[(cc & 0x7F) as usize] = value;
+
+        if cc < CC_LSB_FIRST {
+            // a new MSB clears the LSB, so that controllers which only send the MSB still reach full scale
+            self.cc_values[(cc + CC_LSB_FIRST) as usize] = 0;
+            self.fine_ccs &= !(1 << cc);
+        } else if cc < CC_LSB_FIRST * 2 {
+            self.fine_ccs |= 1 << (cc - CC_LSB_FIRST);
+        }
+
+        if is_learnable(cc) {
+            if let Some(slot) = self.learning_slot.take() {
+                self.cc_map[slot] = Some(cc);
+            }
+        }
+
+        match cc {
+            // the 7-bit value is spread over the 14-bit range, so that full scale is still full scale
+            CC_VCF_CUTOFF => self.vcf_cutoff = ((value as u16) << 7) | value as u16,
+            CC_PORTAMENTO_SWITCH => self.portamento_enabled = U7_HALF_SCALE <= value,
+            CC_SUSTAIN_SWITCH => {
+                self.sustain_enabled = U7_HALF_SCALE <= value;
+                return self.release_unheld_notes();
+            }
+            CC_SOSTENUTO_SWITCH => {
+                let pressed = U7_HALF_SCALE <= value;
+                if pressed && !self.sostenuto_enabled {
+                    // only the notes which are physically held down are caught, not ones which are already released
+                    self.sostenuto_notes = self.held_notes;
+                } else if !pressed {
+                    self.sostenuto_notes = 0;
+                }
+                self.sostenuto_enabled = pressed;
+                return self.release_unheld_notes();
+            }
+            CC_RPN_MSB => self.select_parameter(true, Some(value), None),
+            CC_RPN_LSB => self.select_parameter(true, None, Some(value)),
+            CC_NRPN_MSB => self.select_parameter(false, Some(value), None),
+            CC_NRPN_LSB => self.select_parameter(false, None, Some(value)),
+            CC_DATA_ENTRY_MSB => self.update_parameter(|_| (value as u16) << 7),
+            CC_DATA_ENTRY_LSB => self.update_parameter(|v| (v & !0x7F) | value as u16),
+            CC_DATA_INCREMENT => self.step_parameter(1),
+            CC_DATA_DECREMENT => self.step_parameter(-1),
+            CC_ALL_CONTROLLERS_OFF => return self.reset_controllers(),
+            CC_LOCAL_CONTROL => self.local_control = U7_HALF_SCALE <= value,
+            // the omni and mono/poly mode messages also turn all notes off
+            CC_OMNI_OFF => return self.set_omni(false),
+            CC_OMNI_ON => return self.set_omni(true),
+            CC_MONO_ON => {
+                self.mono_mode = true;
+                return self.all_notes_off();
+            }
+            CC_POLY_ON => {
+                self.mono_mode = false;
+                return self.all_notes_off();
+            }
+            CC_ALL_NOTES_OFF => return self.all_notes_off(),
+            _ => (), // ignore all other MIDI CC messages
+        }
+        None
+    }
+
+    /// `ch.all_notes_off()` forgets every note, including notes held by the pedals
+    pub(crate) fn all_notes_off(&mut self) -> Option<ChannelEvent> {
+        self.held_notes = 0;
+        self.sostenuto_notes = 0;
+        self.released_notes = 0;
+        Some(ChannelEvent::AllNotesOff)
+    }
+
+    /// `ch.listens_to(c)` is true iff messages on channel `c` are handled
+    fn listens_to(&self, channel: u8) -> bool {
+        self.omni || self.channel_mask & (1 << (channel & 0x0F)) != 0
+    }
+
+    /// `ch.set_omni(o)` turns omni mode on if `o` is true, else off, and turns all notes off
+    ///
+    /// Notes which were played on channels that are no longer heard would otherwise never be released.
+    pub(crate) fn set_omni(&mut self, omni: bool) -> Option<ChannelEvent> {
+        self.omni = omni;
+        self.all_notes_off()
+    }
+
+    pub(crate) fn omni(&self) -> bool {
+        self.omni
+    }
+
+    /// `ch.set_channel_mask(m)` listens to the channels set in `m`, bit 0 is channel 0 and so on, and turns all notes
+    /// off
+    pub(crate) fn set_channel_mask(&mut self, mask: u16) -> Option<ChannelEvent> {
+        self.channel_mask = mask;
+        self.all_notes_off()
+    }
+
+    pub(crate) fn channel_mask(&self) -> u16 {
+        self.channel_mask
+    }
+
+    pub(crate) fn mono_mode(&self) -> bool {
+        self.mono_mode
+    }
+
+    pub(crate) fn local_control(&self) -> bool {
+        self.local_control
+    }
+
+    /// `ch.select_parameter(r, msb, lsb)` changes the `msb` or `lsb` of the selected RPN if `r` is true, else NRPN
+    ///
+    /// Selecting the other kind of parameter starts from the null parameter number, which deselects the parameter.
+    fn select_parameter(&mut self, registered: bool, msb: Option<u8>, lsb: Option<u8>) {
+        let current = match self.selected_parameter {
+            Some(Parameter::Registered(n)) if registered => n,
+            Some(Parameter::NonRegistered(n)) if !registered => n,
+            _ => PARAMETER_NULL,
+        };
+        let number = msb.map_or(current & !0x7F, |m| (m as u16) << 7)
+            | lsb.map_or(current & 0x7F, |l| l as u16);
+
+        self.selected_parameter = match number {
+            PARAMETER_NULL => None,
+            n if registered => Some(Parameter::Registered(n)),
+            n => Some(Parameter::NonRegistered(n)),
+        };
+    }
+
+    /// `ch.update_parameter(f)` sets the selected parameter to `f(v)` where `v` is its current 14-bit value
+    fn update_parameter(&mut self, f: impl FnOnce(u16) -> u16) {
+        if let Some(parameter) = self.selected_parameter {
+            let value = f(self.parameter_value(parameter));
+            self.set_parameter_value(parameter, value);
+        }
+    }
+
+    /// `ch.step_parameter(n)` adds `n` to the selected parameter, clamped to the 14-bit range
+    ///
+    /// Pitch-bend sensitivity steps in cents, carrying into the semitones.
+    fn step_parameter(&mut self, step: i32) {
+        if self.selected_parameter == Some(PITCH_BEND_SENSITIVITY) {
+            let cents = bend_range_cents(self.bend_range_up) as i32 + step;
+            let range = cents.max(0) as f32 / 100.0_f32;
+            self.set_pitch_bend_range(range, range);
+        } else {
+            self.update_parameter(|v| (v as i32 + step).clamp(0, U14_MAX as i32) as u16);
+        }
+    }
+
+    /// `ch.parameter_value(p)` is the 14-bit value of parameter `p`, or zero if it isn't assigned to a slot
+    ///
+    /// Pitch-bend sensitivity is always known, the MSB is semitones and the LSB is cents. The VCF cutoff parameter is
+    /// also always known.
+    fn parameter_value(&self, parameter: Parameter) -> u16 {
+        if parameter == PITCH_BEND_SENSITIVITY {
+            let cents = bend_range_cents(self.bend_range_up);
+            ((cents / 100) << 7) | (cents % 100)
+        } else if Some(parameter) == self.vcf_cutoff_parameter {
+            self.vcf_cutoff
+        } else {
+            self.parameter_map
+                .iter()
+                .position(|p| *p == Some(parameter))
+                .map_or(0, |slot| self.parameter_values[slot])
+        }
+    }
+
+    /// `ch.set_parameter_value(p, v)` sets parameter `p` to 14-bit value `v`, cents above 99 are treated as 99
+    fn set_parameter_value(&mut self, parameter: Parameter, value: u16) {
+        if parameter == PITCH_BEND_SENSITIVITY {
+            let range = (value >> 7) as f32 + (value & 0x7F).min(99) as f32 / 100.0_f32;
+            self.set_pitch_bend_range(range, range);
+        }
+        if Some(parameter) == self.vcf_cutoff_parameter {
+            self.vcf_cutoff = value;
+        }
+        for (p, v) in self
+            .parameter_map
+            .iter()
+            .zip(self.parameter_values.iter_mut())
+        {
+            if *p == Some(parameter) {
+                *v = value;
+            }
+        }
+    }
+
+    /// `ch.is_held_by_pedal(n)` is true iff note `n` should keep sounding after it is released, because of the pedals
+    fn is_held_by_pedal(&self, note: u8) -> bool {
+        self.sustain_enabled
+            || (self.sostenuto_enabled && self.sostenuto_notes & note_bit(note) != 0)
+    }
+
+    /// `ch.release_unheld_notes()` lets go of the released notes which are no longer held by the pedals
+    fn release_unheld_notes(&mut self) -> Option<ChannelEvent> {
+        let to_release = notes_in(self.released_notes)
+            .filter(|n| !self.is_held_by_pedal(*n))
+            .fold(0, |notes, n| notes | note_bit(n));
+
+        self.released_notes &= !to_release;
+
+        if to_release == 0 {
+            None
+        } else {
+            Some(ChannelEvent::ReleaseNotes(to_release))
+        }
+    }
+
+    /// `ch.reset_controllers()` resets all implemented MIDI controllers to their default values
+    ///
+    /// The pedals are lifted, so any notes they were holding are released. The pitch-bend range is kept, but the
+    /// selected parameter number is cleared.
+    fn reset_controllers(&mut self) -> Option<ChannelEvent> {
+        self.pitch_bend = 0.0_f32;
+        self.channel_pressure = 0;
+        self.key_pressures = [0; 128];
+        self.selected_parameter = None;
+        self.cc_values = [0; 128];
+        self.fine_ccs = 0;
+        self.vcf_cutoff = 0;
+        self.portamento_enabled = true;
+        self.sustain_enabled = false;
+        self.sostenuto_enabled = false;
+        self.sostenuto_notes = 0;
+        self.release_unheld_notes()
+    }
+
+    /// `ch.held_notes()` is the notes whose keys are physically held down, one bit per MIDI note
+    pub(crate) fn held_notes(&self) -> u128 {
+        self.held_notes
+    }
+
+    pub(crate) fn pitch_bend(&self) -> f32 {
+        self.pitch_bend
+    }
+
+    /// `ch.channel_pressure()` is the current channel pressure in `[0.0, 1.0]`
+    pub(crate) fn channel_pressure(&self) -> f32 {
+        self.channel_pressure as f32 / 127.0_f32
+    }
+
+    /// `ch.key_pressure(n)` is the current key pressure of note `n` in `[0.0, 1.0]`
+    pub(crate) fn key_pressure(&self, note: u8) -> f32 {
+        self.key_pressures[(note & 0x7F) as usize] as f32 / 127.0_f32
+    }
+
+    /// `ch.pitch_bend_semitones()` is the current pitch bend in semitones, scaled by the bend range
+    pub(crate) fn pitch_bend_semitones(&self) -> f32 {
+        if self.pitch_bend < 0.0_f32 {
+            self.pitch_bend * self.bend_range_down
+        } else {
+            self.pitch_bend * self.bend_range_up
+        }
+    }
+
+    /// `ch.set_pitch_bend_range(u, d)` sets the bend range to `u` semitones up and `d` semitones down
+    ///
+    /// The ranges are clamped to `[0.0, MAX_PITCH_BEND_RANGE]`
+    pub(crate) fn set_pitch_bend_range(&mut self, up: f32, down: f32) {
+        self.bend_range_up = up.clamp(0.0_f32, MAX_PITCH_BEND_RANGE);
+        self.bend_range_down = down.clamp(0.0_f32, MAX_PITCH_BEND_RANGE);
+    }
+
+    /// `ch.pitch_bend_range()` is the bend range up and down in semitones
+    pub(crate) fn pitch_bend_range(&self) -> (f32, f32) {
+        (self.bend_range_up, self.bend_range_down)
+    }
+
+    /// `ch.cc_value(c)` is the value of CC number `c` in `[0.0, 1.0]`, with 14-bit resolution if its LSB was sent
+    pub(crate) fn cc_value(&self, cc: u8) -> f32 {
+        let cc = cc & 0x7F;
+        let msb = self.cc_values[cc as usize];
+        if cc < CC_LSB_FIRST && self.fine_ccs & (1 << cc) != 0 {
+            let lsb = self.cc_values[(cc + CC_LSB_FIRST) as usize];
+            (((msb as u16) << 7) | lsb as u16) as f32 / U14_MAX as f32
+        } else {
+            msb as f32 / 127.0_f32
+        }
+    }
+
+    pub(crate) fn mod_wheel(&self) -> f32 {
+        self.cc_value(CC_MOD_WHEEL)
+    }
+
+    pub(crate) fn volume(&self) -> f32 {
+        self.cc_value(CC_VOLUME)
+    }
+
+    /// `ch.vcf_cutoff()` is the VCF cutoff in `[0.0, 1.0]`, from the VCF cutoff CC or parameter, whichever was last
+    pub(crate) fn vcf_cutoff(&self) -> f32 {
+        self.vcf_cutoff as f32 / U14_MAX as f32
+    }
+
+    /// `ch.set_vcf_cutoff_parameter(p)` makes parameter number `p` set the VCF cutoff, or no parameter if `p` is `None`
+    pub(crate) fn set_vcf_cutoff_parameter(&mut self, parameter: Option<Parameter>) {
+        self.vcf_cutoff_parameter = parameter.map(parameter_in_range);
+    }
+
+    pub(crate) fn vcf_cutoff_parameter(&self) -> Option<Parameter> {
+        self.vcf_cutoff_parameter
+    }
+
+    pub(crate) fn vcf_resonance(&self) -> f32 {
+        self.cc_value(CC_VCF_RESONANCE)
+    }
+
+    pub(crate) fn portamento_time(&self) -> f32 {
+        self.cc_value(CC_PORTAMENTO_TIME)
+    }
+
+    pub(crate) fn portamento_enabled(&self) -> bool {
+        self.portamento_enabled
+    }
+
+    pub(crate) fn sustain_enabled(&self) -> bool {
+        self.sustain_enabled
+    }
+
+    pub(crate) fn sostenuto_enabled(&self) -> bool {
+        self.sostenuto_enabled
+    }
+
+    /// `ch.assign_cc(s, c)` assigns CC number `c` to slot `s`, slots past the end are ignored
+    pub(crate) fn assign_cc(&mut self, slot: usize, cc: u8) {
+        if let Some(mapped) = self.cc_map.get_mut(slot) {
+            *mapped = Some(cc & 0x7F);
+        }
+    }
+
+    /// `ch.unassign_cc(s)` removes the CC number assigned to slot `s`
+    pub(crate) fn unassign_cc(&mut self, slot: usize) {
+        if let Some(mapped) = self.cc_map.get_mut(slot) {
+            *mapped = None;
+        }
+    }
+
+    /// `ch.assigned_cc(s)` is the CC number assigned to slot `s`, if any
+    pub(crate) fn assigned_cc(&self, slot: usize) -> Option<u8> {
+        self.cc_map.get(slot).copied().flatten()
+    }
+
+    /// `ch.learn_cc(s)` assigns the next learnable CC received to slot `s`, slots past the end are ignored
+    pub(crate) fn learn_cc(&mut self, slot: usize) {
+        if slot < MAX_CC_SLOTS {
+            self.learning_slot = Some(slot);
+        }
+    }
+
+    /// `ch.cancel_learn()` stops MIDI learn without assigning a CC
+    pub(crate) fn cancel_learn(&mut self) {
+        self.learning_slot = None;
+    }
+
+    /// `ch.learning_slot()` is the slot waiting for a CC to be learned, if MIDI learn is active
+    pub(crate) fn learning_slot(&self) -> Option<usize> {
+        self.learning_slot
+    }
+
+    /// `ch.cc(s)` is the value of the CC assigned to slot `s` in `[0.0, 1.0]`, or zero if no CC is assigned
+    pub(crate) fn cc(&self, slot: usize) -> f32 {
+        self.assigned_cc(slot)
+            .map_or(0.0_f32, |cc| self.cc_value(cc))
+    }
+
+    /// `ch.assign_parameter(s, p)` assigns parameter number `p` to parameter slot `s`, slots past the end are ignored
+    pub(crate) fn assign_parameter(&mut self, slot: usize, parameter: Parameter) {
+        let parameter = parameter_in_range(parameter);
+        let value = self.parameter_value(parameter);
+        if let (Some(p), Some(v)) = (
+            self.parameter_map.get_mut(slot),
+            self.parameter_values.get_mut(slot),
+        ) {
+            *p = Some(parameter);
+            *v = value;
+        }
+    }
+
+    /// `ch.unassign_parameter(s)` removes the parameter number assigned to parameter slot `s`
+    pub(crate) fn unassign_parameter(&mut self, slot: usize) {
+        if let Some(p) = self.parameter_map.get_mut(slot) {
+            *p = None;
+        }
+    }
+
+    /// `ch.assigned_parameter(s)` is the parameter number assigned to parameter slot `s`, if any
+    pub(crate) fn assigned_parameter(&self, slot: usize) -> Option<Parameter> {
+        self.parameter_map.get(slot).copied().flatten()
+    }
+
+    /// `ch.parameter(s)` is the value of the parameter assigned to slot `s` in `[0.0, 1.0]`, or zero if there is none
+    pub(crate) fn parameter(&self, slot: usize) -> f32 {
+        match self.assigned_parameter(slot) {
+            Some(_) => self.parameter_values[slot] as f32 / U14_MAX as f32,
+            None => 0.0_f32,
+        }
+    }
+}
+
+/// `note_bit(n)` is MIDI note `n` as a single set bit, for keeping track of many notes at once
+pub(crate) fn note_bit(note: u8) -> u128 {
+    1 << (note & 0x7F)
+}
+
+/// `notes_in(m)` is an iterator over the MIDI notes which are set in bitfield `m`, from lowest to highest
+pub(crate) fn notes_in(mask: u128) -> impl Iterator<Item = u8> {
+    (0..=127).filter(move |n| mask & note_bit(*n) != 0)
+}
+
+/// `parameter_in_range(p)` is parameter `p` with its number masked to 14 bits
+fn parameter_in_range(parameter: Parameter) -> Parameter {
+    match parameter {
+        Parameter::Registered(n) => Parameter::Registered(n & U14_MAX),
+        Parameter::NonRegistered(n) => Parameter::NonRegistered(n & U14_MAX),
+    }
+}
+
+/// `bend_range_cents(r)` is pitch-bend range `r` in semitones rounded to whole cents
+fn bend_range_cents(range: f32) -> u16 {
+    (range * 100.0_f32 + 0.5_f32) as u16
+}
+
+/// `is_learnable(c)` is true iff CC number `c` may be bound with MIDI learn
+///
+/// The CCs used to select and set parameter numbers, and the channel mode messages, are part of longer sequences
+/// rather than controls a user would want to bind.
+fn is_learnable(cc: u8) -> bool {
+    !matches!(
+        cc,
+        CC_DATA_ENTRY_MSB | CC_DATA_ENTRY_LSB | CC_DATA_INCREMENT..=CC_RPN_MSB | CC_CHANNEL_MODE_FIRST..
+    )
+}
+
+///`value7_to_f32(v)` is the Value7 converted to f32 in `[0.0, 1.0]`
+fn value7_to_f32(val7: Value7) -> f32 {
+    u8::from(val7) as f32 / 127.0_f32
+}
+
+// Common MIDI CC names
+pub(crate) const CC_MOD_WHEEL: u8 = 0x01;
+pub(crate) const CC_DATA_ENTRY_MSB: u8 = 0x06;
+pub(crate) const CC_DATA_ENTRY_LSB: u8 = 0x26;
+pub(crate) const CC_LSB_FIRST: u8 = 0x20;
+pub(crate) const CC_DATA_INCREMENT: u8 = 0x60;
+pub(crate) const CC_DATA_DECREMENT: u8 = 0x61;
+pub(crate) const CC_NRPN_LSB: u8 = 0x62;
+pub(crate) const CC_NRPN_MSB: u8 = 0x63;
+pub(crate) const CC_RPN_LSB: u8 = 0x64;
+pub(crate) const CC_RPN_MSB: u8 = 0x65;
+pub(crate) const CC_VOLUME: u8 = 0x07;
+pub(crate) const CC_VCF_CUTOFF: u8 = 0x47;
+pub(crate) const CC_VCF_RESONANCE: u8 = 0x4A;
+pub(crate) const CC_SUSTAIN_SWITCH: u8 = 0x40;
+pub(crate) const CC_PORTAMENTO_SWITCH: u8 = 0x41;
+pub(crate) const CC_SOSTENUTO_SWITCH: u8 = 0x42;
+pub(crate) const CC_PORTAMENTO_TIME: u8 = 0x05;
+pub(crate) const CC_CHANNEL_MODE_FIRST: u8 = 0x78;
+pub(crate) const CC_ALL_CONTROLLERS_OFF: u8 = 0x79;
+pub(crate) const CC_LOCAL_CONTROL: u8 = 0x7A;
+pub(crate) const CC_ALL_NOTES_OFF: u8 = 0x7B;
+pub(crate) const CC_OMNI_OFF: u8 = 0x7C;
+pub(crate) const CC_OMNI_ON: u8 = 0x7D;
+pub(crate) const CC_MONO_ON: u8 = 0x7E;
+pub(crate) const CC_POLY_ON: u8 = 0x7F;
+
+/// The number of CC slots which CC numbers can be assigned to
+pub const MAX_CC_SLOTS: usize = 16;
+
+/// The number of parameter slots which RPNs and NRPNs can be assigned to
+pub const MAX_PARAMETER_SLOTS: usize = 8;
+
+// the null parameter number means that no parameter is selected
+const PITCH_BEND_SENSITIVITY: Parameter = Parameter::Registered(0x0000);
+const PARAMETER_NULL: u16 = 0x3FFF;
+
+// the largest 14-bit value
+const U14_MAX: u16 = 0x3FFF;
+
+/// The default pitch-bend range in semitones, for both bending up and down
+pub(crate) const DEFAULT_PITCH_BEND_RANGE: f32 = 2.0_f32;
+
+/// The largest pitch-bend range in semitones
+pub(crate) const MAX_PITCH_BEND_RANGE: f32 = 127.0_f32;
+
+// for MIDI CC used as switches values below half scale are considered false and values at-least half scale are true
+const U7_HALF_SCALE: u8 = 1 << 6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(ch: &mut MidiChannel, bytes: &[u8]) -> Option<ChannelEvent> {
+        bytes.iter().fold(None, |_, b| ch.parse(*b))
+    }
+
+    #[test]
+    fn note_events_are_passed_on() {
+        let mut ch = MidiChannel::new(0);
+        assert_eq!(
+            parse_all(&mut ch, &[0x90, 60, 127]),
+            Some(ChannelEvent::NoteOn(60, 1.0))
+        );
+        assert_eq!(
+            parse_all(&mut ch, &[0x80, 60, 0]),
+            Some(ChannelEvent::NoteOff(60))
+        );
+    }
+
+    #[test]
+    fn note_off_without_note_on_is_ignored() {
+        let mut ch = MidiChannel::new(0);
+        assert_eq!(parse_all(&mut ch, &[0x80, 60, 0]), None);
+    }
+
+    #[test]
+    fn other_channels_are_ignored() {
+        let mut ch = MidiChannel::new(0);
+        assert_eq!(parse_all(&mut ch, &[0x91, 60, 127]), None);
+    }
+
+    #[test]
+    fn sustain_releases_all_notes_at_once() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, CC_SUSTAIN_SWITCH, 127]);
+        parse_all(&mut ch, &[0x90, 60, 127, 64, 127]);
+        assert_eq!(parse_all(&mut ch, &[0x80, 60, 0, 64, 0]), None);
+
+        assert_eq!(
+            parse_all(&mut ch, &[0xB0, CC_SUSTAIN_SWITCH, 0]),
+            Some(ChannelEvent::ReleaseNotes(note_bit(60) | note_bit(64)))
+        );
+    }
+
+    #[test]
+    fn reset_controllers_lifts_the_pedals() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, CC_SUSTAIN_SWITCH, 127]);
+        parse_all(&mut ch, &[0x90, 60, 127]);
+        parse_all(&mut ch, &[0x80, 60, 0]);
+
+        assert_eq!(
+            parse_all(&mut ch, &[0xB0, CC_ALL_CONTROLLERS_OFF, 0]),
+            Some(ChannelEvent::ReleaseNotes(note_bit(60)))
+        );
+        assert!(!ch.sustain_enabled());
+    }
+
+    #[test]
+    fn rpn_0_sets_the_pitch_bend_range() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, CC_RPN_MSB, 0, CC_RPN_LSB, 0]);
+        parse_all(&mut ch, &[CC_DATA_ENTRY_MSB, 12, CC_DATA_ENTRY_LSB, 50]);
+        assert_eq!(ch.pitch_bend_range(), (12.5, 12.5));
+
+        // a new MSB clears the cents
+        parse_all(&mut ch, &[CC_DATA_ENTRY_MSB, 7]);
+        assert_eq!(ch.pitch_bend_range(), (7.0, 7.0));
+    }
+
+    #[test]
+    fn data_entry_is_ignored_without_rpn_0() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, CC_DATA_ENTRY_MSB, 12]);
+        parse_all(
+            &mut ch,
+            &[CC_RPN_MSB, 0, CC_RPN_LSB, 1, CC_DATA_ENTRY_MSB, 12],
+        );
+        parse_all(
+            &mut ch,
+            &[CC_RPN_LSB, 0, CC_NRPN_MSB, 0, CC_DATA_ENTRY_MSB, 12],
+        );
+        parse_all(
+            &mut ch,
+            &[CC_RPN_MSB, 0x7F, CC_RPN_LSB, 0x7F, CC_DATA_ENTRY_MSB, 12],
+        );
+        assert_eq!(ch.pitch_bend_range(), (2.0, 2.0));
+    }
+
+    #[test]
+    fn bend_is_scaled_by_the_range_in_each_direction() {
+        let mut ch = MidiChannel::new(0);
+        ch.set_pitch_bend_range(12.0, 2.0);
+        parse_all(&mut ch, &[0xE0, 0x00, 0x00]);
+        assert!((ch.pitch_bend_semitones() + 2.0).abs() < 0.001);
+        parse_all(&mut ch, &[0xE0, 0x7F, 0x7F]);
+        assert!((ch.pitch_bend_semitones() - 12.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn learn_binds_the_next_cc() {
+        let mut ch = MidiChannel::new(0);
+        ch.learn_cc(3);
+        assert_eq!(ch.learning_slot(), Some(3));
+
+        // parameter number CCs are skipped
+        parse_all(&mut ch, &[0xB0, CC_RPN_MSB, 0]);
+        parse_all(&mut ch, &[0xB0, 20, 127]);
+        assert_eq!(ch.assigned_cc(3), Some(20));
+        assert_eq!(ch.learning_slot(), None);
+        assert_eq!(ch.cc(3), 1.0);
+
+        // learning is over, so the next CC is not bound
+        parse_all(&mut ch, &[0xB0, 21, 127]);
+        assert_eq!(ch.assigned_cc(3), Some(20));
+    }
+
+    #[test]
+    fn cc_slots_read_the_last_value() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, 74, 127]);
+        ch.assign_cc(0, 74);
+        assert_eq!(ch.cc(0), 1.0);
+        assert_eq!(ch.cc(1), 0.0);
+
+        ch.unassign_cc(0);
+        assert_eq!(ch.cc(0), 0.0);
+
+        // slots past the end are ignored
+        ch.assign_cc(MAX_CC_SLOTS, 74);
+        ch.learn_cc(MAX_CC_SLOTS);
+        assert_eq!(ch.assigned_cc(MAX_CC_SLOTS), None);
+        assert_eq!(ch.learning_slot(), None);
+    }
+
+    #[test]
+    fn cc_pairs_have_14_bit_resolution() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(
+            &mut ch,
+            &[0xB0, CC_MOD_WHEEL, 64, CC_MOD_WHEEL + CC_LSB_FIRST, 1],
+        );
+        assert_eq!(ch.mod_wheel(), ((64 << 7) + 1) as f32 / 16383.0);
+
+        // an MSB on its own is still 7-bit, so full scale is exactly one
+        parse_all(&mut ch, &[CC_MOD_WHEEL, 127]);
+        assert_eq!(ch.mod_wheel(), 1.0);
+    }
+
+    #[test]
+    fn nrpn_data_entry_sets_assigned_slots() {
+        let mut ch = MidiChannel::new(0);
+        ch.assign_parameter(1, Parameter::NonRegistered(0x0102));
+        parse_all(&mut ch, &[0xB0, CC_NRPN_MSB, 0x02, CC_NRPN_LSB, 0x02]);
+        parse_all(&mut ch, &[CC_DATA_ENTRY_MSB, 0x7F, CC_DATA_ENTRY_LSB, 0x7F]);
+        assert_eq!(ch.parameter(1), 1.0);
+
+        parse_all(&mut ch, &[CC_DATA_DECREMENT, 0]);
+        assert_eq!(ch.parameter(1), 16382.0 / 16383.0);
+        parse_all(&mut ch, &[CC_DATA_INCREMENT, 0, CC_DATA_INCREMENT, 0]);
+        assert_eq!(ch.parameter(1), 1.0);
+
+        // other parameters do not change the slot
+        parse_all(&mut ch, &[CC_NRPN_LSB, 0x03, CC_DATA_ENTRY_MSB, 0]);
+        assert_eq!(ch.parameter(1), 1.0);
+        assert_eq!(ch.parameter(0), 0.0);
+    }
+
+    #[test]
+    fn increment_changes_the_bend_range_by_a_cent() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xB0, CC_RPN_MSB, 0, CC_RPN_LSB, 0]);
+        parse_all(&mut ch, &[CC_DATA_INCREMENT, 0]);
+        assert!((ch.pitch_bend_range().0 - 2.01).abs() < 0.000_1);
+        parse_all(&mut ch, &[CC_DATA_DECREMENT, 0, CC_DATA_DECREMENT, 0]);
+        assert!((ch.pitch_bend_range().1 - 1.99).abs() < 0.000_1);
+
+        // the cents carry into the semitones
+        parse_all(&mut ch, &[CC_DATA_ENTRY_LSB, 99, CC_DATA_INCREMENT, 0]);
+        assert!((ch.pitch_bend_range().0 - 2.0).abs() < 0.000_1);
+    }
+
+    #[test]
+    fn pressure_is_tracked_per_channel_and_key() {
+        let mut ch = MidiChannel::new(0);
+        parse_all(&mut ch, &[0xD0, 127]);
+        parse_all(&mut ch, &[0xA0, 60, 127, 61, 0]);
+        assert_eq!(ch.channel_pressure(), 1.0);
+        assert_eq!(ch.key_pressure(60), 1.0);
+        assert_eq!(ch.key_pressure(61), 0.0);
+
+        // playing the key again starts with no pressure
+        parse_all(&mut ch, &[0x90, 60, 127]);
+        assert_eq!(ch.key_pressure(60), 0.0);
+
+        parse_all(&mut ch, &[0xB0, CC_ALL_CONTROLLERS_OFF, 0]);
+        assert_eq!(ch.channel_pressure(), 0.0);
+    }
+
+    #[test]
+    fn channel_mask_selects_channels() {
+        let mut ch = MidiChannel::new(0);
+        ch.set_channel_mask(0b1010);
+        assert_eq!(parse_all(&mut ch, &[0x90, 60, 127]), None);
+        assert_eq!(
+            parse_all(&mut ch, &[0x91, 60, 127]),
+            Some(ChannelEvent::NoteOn(60, 1.0))
+        );
+        assert_eq!(
+            parse_all(&mut ch, &[0x93, 61, 127]),
+            Some(ChannelEvent::NoteOn(61, 1.0))
+        );
+    }
+
+    #[test]
+    fn omni_messages_turn_notes_off() {
+        let mut ch = MidiChannel::new(0);
+        assert_eq!(
+            parse_all(&mut ch, &[0xB0, CC_OMNI_ON, 0]),
+            Some(ChannelEvent::AllNotesOff)
+        );
+        assert!(ch.omni());
+        assert_eq!(
+            parse_all(&mut ch, &[0x9F, 60, 127]),
+            Some(ChannelEvent::NoteOn(60, 1.0))
+        );
+
+        parse_all(&mut ch, &[0xBF, CC_OMNI_OFF, 0]);
+        assert!(!ch.omni());
+        assert_eq!(parse_all(&mut ch, &[0x9F, 60, 127]), None);
+    }
+
+    #[test]
+    fn mode_messages_are_remembered() {
+        let mut ch = MidiChannel::new(0);
+        assert!(ch.local_control());
+        assert!(!ch.mono_mode());
+
+        parse_all(&mut ch, &[0xB0, CC_LOCAL_CONTROL, 0]);
+        assert!(!ch.local_control());
+        assert_eq!(
+            parse_all(&mut ch, &[CC_MONO_ON, 1]),
+            Some(ChannelEvent::AllNotesOff)
+        );
+        assert!(ch.mono_mode());
+        parse_all(&mut ch, &[CC_POLY_ON, 0]);
+        assert!(!ch.mono_mode());
+    }
+
+    #[test]
+    fn notes_in_lists_the_set_notes() {
+        let mut notes = notes_in(note_bit(0) | note_bit(64) | note_bit(127));
+        assert_eq!(notes.next(), Some(0));
+        assert_eq!(notes.next(), Some(64));
+        assert_eq!(notes.next(), Some(127));
+        assert_eq!(notes.next(), None);
+    }
+}
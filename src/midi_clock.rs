@@ -0,0 +1,390 @@
+//! MIDI Clock Receiver
+//!
+//! A MIDI clock receiver is fed MIDI data in the form of sequential bytes following the MIDI protocol, and follows the
+//! MIDI real-time messages sent by a DAW or sequencer so that LFOs, sequencers, and the like can stay in sync.
+//!
+//! MIDI clock is sent at 24 pulses per quarter note (PPQN). Start, stop, and continue messages control whether the
+//! transport is running, and the song position pointer moves the transport to a position in the song.
+//!
+//! The tempo is found by timing the clock pulses against the sample rate, it is smoothed because clock pulses from a
+//! computer tend to jitter.
+//!
+//! Channel messages such as notes are ignored, so the same bytes may be fed to a MIDI receiver as well.
+
+use midi_convert::{midi_types::MidiMessage, MidiByteStreamParser};
+
+/// A MIDI clock receiver is represented here
+pub struct MidiClock {
+    parser: MidiByteStreamParser,
+
+    sample_rate_hz: f32,
+
+    running: bool,
+
+    // clock pulses since the start of the song, the next pulse plays this position
+    position: u32,
+
+    // samples since the last clock pulse, `None` if there hasn't been a recent pulse
+    samples_since_pulse: Option<u32>,
+
+    // the smoothed time between clock pulses in samples, `None` until the tempo is known
+    pulse_period: Option<f32>,
+
+    // true if the clock pulses stopped, so the next measurement replaces the old tempo instead of being smoothed
+    timed_out: bool,
+
+    // in `(0.0, 1.0]`
+    smoothing: f32,
+
+    beats_per_bar: u32,
+
+    // one bit per division which has had a pulse since it was last read
+    pulses: u16,
+}
+
+impl MidiClock {
+    /// `MidiClock::new(sr)` is a new stopped MIDI clock receiver with sample rate `sr`
+    pub fn new(sample_rate_hz: f32) -> Self {
+        Self {
+            parser: MidiByteStreamParser::new(),
+            sample_rate_hz,
+            running: false,
+            position: 0,
+            samples_since_pulse: None,
+            pulse_period: None,
+            timed_out: false,
+            smoothing: DEFAULT_SMOOTHING,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            pulses: 0,
+        }
+    }
+
+    /// `mc.tick()` advances the MIDI clock receiver by 1 tick, must be called at the sample rate
+    pub fn tick(&mut self) {
+        self.samples_since_pulse = match self.samples_since_pulse {
+            Some(n) if (n as f32) < self.sample_rate_hz * CLOCK_TIMEOUT_SECONDS => Some(n + 1),
+            Some(_) => {
+                // the clock pulses stopped, the tempo is measured from scratch when they return
+                self.timed_out = true;
+                None
+            }
+            None => None,
+        };
+    }
+
+    /// `mc.parse(b)` parses incoming MIDI data in the form of sequential bytes `b` and updates its internal state
+    ///
+    /// It is expected to call this function every time a new MIDI byte is received.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::midi_clock::{Division, MidiClock};
+    ///
+    /// let mut mc = MidiClock::new(24_000.0);
+    /// mc.parse(0xFA); // start
+    ///
+    /// // 120 BPM is 48 pulses per second
+    /// for _ in 0..24 {
+    ///     mc.parse(0xF8);
+    ///     for _ in 0..500 {
+    ///         mc.tick();
+    ///     }
+    /// }
+    ///
+    /// assert!(mc.running());
+    /// assert_eq!(mc.ticks(), 24);
+    /// assert!((mc.bpm() - 120.0).abs() < 0.01);
+    /// assert!(mc.pulse(Division::Quarter));
+    /// ```
+    pub fn parse(&mut self, byte: u8) {
+        if let Some(message) = self.parser.parse(byte) {
+            self.handle_message(message);
+        }
+    }
+
+    /// `mc.handle_message(m)` updates the internal state from MIDI message `m`
+    fn handle_message(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::TimingClock => self.handle_clock(),
+            MidiMessage::Start => {
+                self.running = true;
+                self.position = 0;
+            }
+            MidiMessage::Continue => self.running = true,
+            MidiMessage::Stop => self.running = false,
+            MidiMessage::SongPositionPointer(beats) => {
+                self.position = u16::from(beats) as u32 * PULSES_PER_SIXTEENTH;
+            }
+            _ => (), // ignore all other MIDI messages
+        }
+    }
+
+    /// `mc.handle_clock()` updates the tempo and position after receiving a clock pulse
+    fn handle_clock(&mut self) {
+        if let Some(samples) = self.samples_since_pulse {
+            let samples = samples as f32;
+            self.pulse_period = Some(match self.pulse_period {
+                Some(period) if !self.timed_out => period + (samples - period) * self.smoothing,
+                _ => samples,
+            });
+            self.timed_out = false;
+        }
+        self.samples_since_pulse = Some(0);
+
+        if self.running {
+            for (i, division) in Division::ALL.iter().enumerate() {
+                if self.position.checked_rem(self.pulses_per(*division)) == Some(0) {
+                    self.pulses |= 1 << i;
+                }
+            }
+            self.position = self.position.wrapping_add(1);
+        }
+    }
+
+    /// `mc.pulses_per(d)` is the number of clock pulses in division `d`
+    fn pulses_per(&self, division: Division) -> u32 {
+        match division {
+            Division::ThirtySecond => 3,
+            Division::SixteenthTriplet => 4,
+            Division::Sixteenth => PULSES_PER_SIXTEENTH,
+            Division::EighthTriplet => 8,
+            Division::Eighth => 12,
+            Division::QuarterTriplet => 16,
+            Division::Quarter => PULSES_PER_QUARTER,
+            Division::Half => PULSES_PER_QUARTER * 2,
+            Division::Bar => PULSES_PER_QUARTER * self.beats_per_bar,
+        }
+    }
+
+    /// `mc.running()` is true if the transport is running, after a start or continue message and before a stop
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// `mc.ticks()` is the number of clock pulses played since the start of the song, at 24 PPQN
+    ///
+    /// The song position pointer moves this, and start sets it back to zero.
+    pub fn ticks(&self) -> u32 {
+        self.position
+    }
+
+    /// `mc.song_position()` is the position in the song in MIDI beats, which are sixteenth notes
+    pub fn song_position(&self) -> u32 {
+        self.position / PULSES_PER_SIXTEENTH
+    }
+
+    /// `mc.bpm()` is the smoothed tempo in beats per minute, or zero if the tempo is not known yet
+    ///
+    /// The tempo is measured whether or not the transport is running, it takes two clock pulses to know the tempo.
+    pub fn bpm(&self) -> f32 {
+        match self.pulse_period {
+            Some(period) if 0.0_f32 < period => {
+                SECONDS_PER_MINUTE * self.sample_rate_hz / (period * PULSES_PER_QUARTER as f32)
+            }
+            _ => 0.0_f32,
+        }
+    }
+
+    /// `mc.set_smoothing(s)` sets the tempo smoothing to `s`, clamped to `[MIN_SMOOTHING, 1.0]`
+    ///
+    /// Each clock pulse moves the tempo this fraction of the way towards the newly measured tempo, so smaller values
+    /// are smoother but slower to follow tempo changes. A smoothing of 1.0 means no smoothing.
+    pub fn set_smoothing(&mut self, smoothing: f32) {
+        self.smoothing = smoothing.clamp(MIN_SMOOTHING, 1.0_f32);
+    }
+
+    /// `mc.smoothing()` is the tempo smoothing
+    pub fn smoothing(&self) -> f32 {
+        self.smoothing
+    }
+
+    /// `mc.set_beats_per_bar(n)` sets the number of quarter notes in a bar to `n`, at least 1
+    pub fn set_beats_per_bar(&mut self, beats: u8) {
+        self.beats_per_bar = beats.max(1) as u32;
+    }
+
+    /// `mc.pulse(d)` is true if a new division `d` has started since it was last checked. Self clearing.
+    ///
+    /// Pulses only happen while the transport is running. Divisions line up with the start of the song, so the first
+    /// clock pulse after a start message pulses every division.
+    pub fn pulse(&mut self, division: Division) -> bool {
+        let bit = 1 << division as u16;
+        if self.pulses & bit != 0 {
+            self.pulses &= !bit;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Clock divisions are represented here
+///
+/// Triplet divisions fit three notes in the time of two. The length of a bar is set with `set_beats_per_bar()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Division {
+    ThirtySecond,
+    SixteenthTriplet,
+    Sixteenth,
+    EighthTriplet,
+    Eighth,
+    QuarterTriplet,
+    Quarter,
+    Half,
+    Bar,
+}
+
+impl Division {
+    const ALL: [Self; 9] = [
+        Self::ThirtySecond,
+        Self::SixteenthTriplet,
+        Self::Sixteenth,
+        Self::EighthTriplet,
+        Self::Eighth,
+        Self::QuarterTriplet,
+        Self::Quarter,
+        Self::Half,
+        Self::Bar,
+    ];
+}
+
+/// The default tempo smoothing
+pub const DEFAULT_SMOOTHING: f32 = 0.1_f32;
+
+/// The smallest tempo smoothing, below this the tempo would barely move
+pub const MIN_SMOOTHING: f32 = 0.001_f32;
+
+const DEFAULT_BEATS_PER_BAR: u32 = 4;
+
+const PULSES_PER_QUARTER: u32 = 24;
+const PULSES_PER_SIXTEENTH: u32 = PULSES_PER_QUARTER / 4;
+
+const SECONDS_PER_MINUTE: f32 = 60.0_f32;
+
+// a gap between clock pulses longer than this means that the clock stopped, about 2.5 BPM
+const CLOCK_TIMEOUT_SECONDS: f32 = 1.0_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 24_000.0;
+
+    fn clock(mc: &mut MidiClock, samples: u32) {
+        mc.parse(0xF8);
+        for _ in 0..samples {
+            mc.tick();
+        }
+    }
+
+    #[test]
+    fn new_clock_is_stopped() {
+        let mc = MidiClock::new(SAMPLE_RATE);
+        assert!(!mc.running());
+        assert_eq!(mc.bpm(), 0.0);
+        assert_eq!(mc.ticks(), 0);
+    }
+
+    #[test]
+    fn ticks_only_count_while_running() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        clock(&mut mc, 500);
+        assert_eq!(mc.ticks(), 0);
+
+        mc.parse(0xFA);
+        clock(&mut mc, 500);
+        clock(&mut mc, 500);
+        assert_eq!(mc.ticks(), 2);
+
+        mc.parse(0xFC);
+        clock(&mut mc, 500);
+        assert!(!mc.running());
+        assert_eq!(mc.ticks(), 2);
+
+        mc.parse(0xFB);
+        clock(&mut mc, 500);
+        assert!(mc.running());
+        assert_eq!(mc.ticks(), 3);
+
+        mc.parse(0xFA);
+        assert_eq!(mc.ticks(), 0);
+    }
+
+    #[test]
+    fn song_position_pointer_moves_the_position() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        // 0x0102 sixteenths
+        for b in [0xF2, 0x02, 0x02] {
+            mc.parse(b);
+        }
+        assert_eq!(mc.song_position(), 0x0102);
+        assert_eq!(mc.ticks(), 0x0102 * 6);
+    }
+
+    #[test]
+    fn tempo_changes_are_smoothed() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        clock(&mut mc, 500);
+        clock(&mut mc, 250);
+        assert!((mc.bpm() - 120.0).abs() < 0.01);
+
+        // the new tempo is 240 BPM, the smoothed tempo is on its way there
+        clock(&mut mc, 250);
+        assert!(120.0 < mc.bpm() && mc.bpm() < 240.0);
+
+        mc.set_smoothing(1.0);
+        clock(&mut mc, 250);
+        assert!((mc.bpm() - 240.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn tempo_is_measured_from_scratch_after_a_timeout() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        clock(&mut mc, 500);
+        clock(&mut mc, SAMPLE_RATE as u32 * 2);
+        assert!((mc.bpm() - 120.0).abs() < 0.01);
+
+        // the first pulse after the timeout can't be timed, the second one can
+        clock(&mut mc, 250);
+        mc.parse(0xF8);
+        assert!((mc.bpm() - 240.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn divisions_pulse_on_their_boundaries() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        mc.parse(0xFA);
+        clock(&mut mc, 500);
+        assert!(mc.pulse(Division::Bar));
+        assert!(mc.pulse(Division::Quarter));
+        assert!(mc.pulse(Division::Sixteenth));
+        assert!(!mc.pulse(Division::Sixteenth));
+
+        let mut sixteenths = 0;
+        let mut quarters = 0;
+        let mut bars = 0;
+        for _ in 1..96 * 2 {
+            clock(&mut mc, 500);
+            sixteenths += mc.pulse(Division::Sixteenth) as u32;
+            quarters += mc.pulse(Division::Quarter) as u32;
+            bars += mc.pulse(Division::Bar) as u32;
+        }
+        assert_eq!(sixteenths, 31);
+        assert_eq!(quarters, 7);
+        assert_eq!(bars, 1);
+    }
+
+    #[test]
+    fn bar_length_follows_beats_per_bar() {
+        let mut mc = MidiClock::new(SAMPLE_RATE);
+        mc.set_beats_per_bar(3);
+        mc.parse(0xFA);
+        for _ in 0..72 {
+            clock(&mut mc, 500);
+        }
+        mc.pulse(Division::Bar);
+        clock(&mut mc, 500);
+        assert!(mc.pulse(Division::Bar));
+    }
+}
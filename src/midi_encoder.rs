@@ -0,0 +1,373 @@
+//! MIDI Encoder
+//!
+//! A MIDI encoder turns messages such as notes, CCs, and clock pulses into sequential bytes following the MIDI
+//! protocol, so that controllers like a `RibbonController` or a sequencer can drive external gear.
+//!
+//! Each message is written into a buffer supplied by the caller, and the number of bytes written is returned. The
+//! bytes can then be sent out over a UART or USB.
+//!
+//! Running status is used to save bandwidth, when a channel message has the same status byte as the one before it the
+//! status byte is left out. Real-time messages may be sent between other messages without breaking running status.
+//!
+//! Values are given in the same ranges that the MIDI receivers use, so velocity is in `[0.0, 1.0]` and pitch bend is
+//! in `[-1.0, 1.0]`. Channels are zero-based.
+
+/// A MIDI encoder is represented here
+pub struct MidiEncoder {
+    // the status byte of the last channel message written, `None` if the next message must send its status byte
+    running_status: Option<u8>,
+
+    running_status_enabled: bool,
+}
+
+#[allow(clippy::new_without_default)]
+impl MidiEncoder {
+    /// `MidiEncoder::new()` is a new MIDI encoder with running status enabled
+    pub fn new() -> Self {
+        Self {
+            running_status: None,
+            running_status_enabled: true,
+        }
+    }
+
+    /// `me.note_on(buf, c, n, v)` writes a note-on for note `n` with velocity `v` on channel `c` into `buf`
+    ///
+    /// Velocity is clamped to `[0.0, 1.0]`, and is at least 1 in MIDI terms because a velocity of zero means note-off.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::midi_encoder::MidiEncoder;
+    ///
+    /// let mut me = MidiEncoder::new();
+    /// let mut buf = [0; 8];
+    ///
+    /// let mut n = me.note_on(&mut buf, 0, 60, 1.0).unwrap();
+    /// n += me.note_on(&mut buf[n..], 0, 64, 1.0).unwrap();
+    ///
+    /// // the second status byte is left out thanks to running status
+    /// assert_eq!(&buf[..n], &[0x90, 60, 127, 64, 127]);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn note_on(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    ) -> Result<usize, EncodeError> {
+        let velocity = f32_to_u7(velocity).max(1);
+        self.channel_message(buf, NOTE_ON, channel, &[note & 0x7F, velocity])
+    }
+
+    /// `me.note_off(buf, c, n, v)` writes a note-off for note `n` with release velocity `v` on channel `c` into `buf`
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn note_off(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    ) -> Result<usize, EncodeError> {
+        let velocity = f32_to_u7(velocity);
+        self.channel_message(buf, NOTE_OFF, channel, &[note & 0x7F, velocity])
+    }
+
+    /// `me.control_change(buf, c, cc, v)` writes CC number `cc` with value `v` in `[0.0, 1.0]` on channel `c` into `buf`
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn control_change(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        cc: u8,
+        value: f32,
+    ) -> Result<usize, EncodeError> {
+        let value = f32_to_u7(value);
+        self.channel_message(buf, CONTROL_CHANGE, channel, &[cc & 0x7F, value])
+    }
+
+    /// `me.pitch_bend(buf, c, b)` writes a pitch bend of `b` in `[-1.0, 1.0]` on channel `c` into `buf`
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn pitch_bend(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        bend: f32,
+    ) -> Result<usize, EncodeError> {
+        let bend = bend.clamp(-1.0_f32, 1.0_f32);
+        // the center is 8192, there is one less step above the center than below it
+        let scale = if 0.0_f32 < bend {
+            8191.0_f32
+        } else {
+            8192.0_f32
+        };
+        let value = (PITCH_BEND_CENTER + bend * scale + 0.5_f32) as u16;
+        let bytes = [(value & 0x7F) as u8, (value >> 7) as u8];
+        self.channel_message(buf, PITCH_BEND, channel, &bytes)
+    }
+
+    /// `me.channel_pressure(buf, c, p)` writes channel aftertouch `p` in `[0.0, 1.0]` on channel `c` into `buf`
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn channel_pressure(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        pressure: f32,
+    ) -> Result<usize, EncodeError> {
+        let pressure = f32_to_u7(pressure);
+        self.channel_message(buf, CHANNEL_PRESSURE, channel, &[pressure])
+    }
+
+    /// `me.key_pressure(buf, c, n, p)` writes polyphonic aftertouch `p` in `[0.0, 1.0]` for note `n` on channel `c`
+    /// into `buf`
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is too small for the message.
+    pub fn key_pressure(
+        &mut self,
+        buf: &mut [u8],
+        channel: u8,
+        note: u8,
+        pressure: f32,
+    ) -> Result<usize, EncodeError> {
+        let pressure = f32_to_u7(pressure);
+        self.channel_message(buf, KEY_PRESSURE, channel, &[note & 0x7F, pressure])
+    }
+
+    /// `me.real_time(buf, m)` writes real-time message `m` into `buf`
+    ///
+    /// Real-time messages do not change the running status, so they may be sent at any time.
+    ///
+    /// # Errors
+    ///
+    /// Nothing is written if the buffer is empty.
+    pub fn real_time(&mut self, buf: &mut [u8], message: RealTime) -> Result<usize, EncodeError> {
+        let status = match message {
+            RealTime::TimingClock => 0xF8,
+            RealTime::Start => 0xFA,
+            RealTime::Continue => 0xFB,
+            RealTime::Stop => 0xFC,
+            RealTime::ActiveSensing => 0xFE,
+            RealTime::Reset => 0xFF,
+        };
+        if message == RealTime::Reset {
+            // receivers forget the running status when they reset
+            self.running_status = None;
+        }
+        write(buf, &[status])
+    }
+
+    /// `me.set_running_status(e)` enables running status if `e` is true, else every message has its status byte
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.running_status_enabled = enabled;
+        self.running_status = None;
+    }
+
+    /// `me.running_status()` is true if running status is enabled
+    pub fn running_status(&self) -> bool {
+        self.running_status_enabled
+    }
+
+    /// `me.reset_running_status()` makes the next channel message send its status byte
+    ///
+    /// This should be called whenever other bytes are sent on the same output, such as system exclusive messages or
+    /// messages merged in from another MIDI input. Calling it every so often lets a receiver which was plugged in
+    /// midway through catch up.
+    pub fn reset_running_status(&mut self) {
+        self.running_status = None;
+    }
+
+    /// `me.channel_message(buf, s, c, ds)` writes the channel message with status `s` on channel `c` and data bytes
+    /// `ds` into `buf`, leaving out the status byte if it is the running status
+    fn channel_message(
+        &mut self,
+        buf: &mut [u8],
+        status: u8,
+        channel: u8,
+        data: &[u8],
+    ) -> Result<usize, EncodeError> {
+        let status = status | channel.min(15);
+
+        let n = if self.running_status_enabled && self.running_status == Some(status) {
+            write(buf, data)?
+        } else {
+            let mut bytes = [status, 0, 0];
+            bytes[1..=data.len()].copy_from_slice(data);
+            write(buf, &bytes[..=data.len()])?
+        };
+
+        if self.running_status_enabled {
+            self.running_status = Some(status);
+        }
+        Ok(n)
+    }
+}
+
+/// MIDI real-time messages are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealTime {
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+/// Errors which can happen when encoding MIDI messages are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The buffer is too small to hold the message
+    BufferTooSmall,
+}
+
+/// `write(buf, bs)` copies bytes `bs` to the start of `buf`, and is the number of bytes written
+fn write(buf: &mut [u8], bytes: &[u8]) -> Result<usize, EncodeError> {
+    buf.get_mut(..bytes.len())
+        .ok_or(EncodeError::BufferTooSmall)?
+        .copy_from_slice(bytes);
+    Ok(bytes.len())
+}
+
+/// `f32_to_u7(v)` is `v` in `[0.0, 1.0]` converted to a 7-bit MIDI value, rounding to the nearest value
+fn f32_to_u7(val: f32) -> u8 {
+    (val.clamp(0.0_f32, 1.0_f32) * 127.0_f32 + 0.5_f32) as u8
+}
+
+// Channel message status bytes, without the channel
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const KEY_PRESSURE: u8 = 0xA0;
+const CONTROL_CHANGE: u8 = 0xB0;
+const CHANNEL_PRESSURE: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+
+const PITCH_BEND_CENTER: f32 = 8192.0_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mono_midi_receiver::MonoMidiReceiver;
+
+    #[test]
+    fn running_status_leaves_out_repeated_status_bytes() {
+        let mut me = MidiEncoder::new();
+        let mut buf = [0; 3];
+        assert_eq!(me.control_change(&mut buf, 2, 1, 1.0), Ok(3));
+        assert_eq!(buf, [0xB2, 1, 127]);
+        assert_eq!(me.control_change(&mut buf, 2, 1, 0.0), Ok(2));
+        assert_eq!(buf[..2], [1, 0]);
+
+        // a different channel is a different status
+        assert_eq!(me.control_change(&mut buf, 3, 1, 0.0), Ok(3));
+    }
+
+    #[test]
+    fn real_time_keeps_running_status() {
+        let mut me = MidiEncoder::new();
+        let mut buf = [0; 3];
+        me.note_on(&mut buf, 0, 60, 1.0).unwrap();
+        assert_eq!(me.real_time(&mut buf, RealTime::TimingClock), Ok(1));
+        assert_eq!(buf[0], 0xF8);
+        assert_eq!(me.note_on(&mut buf, 0, 62, 1.0), Ok(2));
+
+        // but a reset forgets it
+        me.real_time(&mut buf, RealTime::Reset).unwrap();
+        assert_eq!(me.note_on(&mut buf, 0, 62, 1.0), Ok(3));
+    }
+
+    #[test]
+    fn running_status_can_be_turned_off() {
+        let mut me = MidiEncoder::new();
+        me.set_running_status(false);
+        let mut buf = [0; 3];
+        me.note_on(&mut buf, 0, 60, 1.0).unwrap();
+        assert_eq!(me.note_on(&mut buf, 0, 60, 1.0), Ok(3));
+
+        me.set_running_status(true);
+        me.note_on(&mut buf, 0, 60, 1.0).unwrap();
+        me.reset_running_status();
+        assert_eq!(me.note_on(&mut buf, 0, 60, 1.0), Ok(3));
+    }
+
+    #[test]
+    fn small_buffers_are_not_written() {
+        let mut me = MidiEncoder::new();
+        let mut buf = [0; 2];
+        assert_eq!(
+            me.note_on(&mut buf, 0, 60, 1.0),
+            Err(EncodeError::BufferTooSmall)
+        );
+        assert_eq!(buf, [0, 0]);
+
+        // the failed message didn't set the running status
+        assert_eq!(
+            me.note_on(&mut buf, 0, 60, 1.0),
+            Err(EncodeError::BufferTooSmall)
+        );
+        assert_eq!(
+            me.real_time(&mut [], RealTime::Start),
+            Err(EncodeError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn zero_velocity_note_on_is_still_a_note_on() {
+        let mut me = MidiEncoder::new();
+        let mut buf = [0; 3];
+        me.note_on(&mut buf, 0, 60, 0.0).unwrap();
+        assert_eq!(buf, [0x90, 60, 1]);
+    }
+
+    #[test]
+    fn pitch_bend_covers_the_full_range() {
+        let mut me = MidiEncoder::new();
+        me.set_running_status(false);
+        let mut buf = [0; 3];
+        me.pitch_bend(&mut buf, 0, -1.0).unwrap();
+        assert_eq!(buf, [0xE0, 0x00, 0x00]);
+        me.pitch_bend(&mut buf, 0, 0.0).unwrap();
+        assert_eq!(buf, [0xE0, 0x00, 0x40]);
+        me.pitch_bend(&mut buf, 0, 1.0).unwrap();
+        assert_eq!(buf, [0xE0, 0x7F, 0x7F]);
+    }
+
+    #[test]
+    fn encoded_messages_round_trip_through_a_receiver() {
+        let mut me = MidiEncoder::new();
+        let mut mr = MonoMidiReceiver::new(5);
+        let mut buf = [0; 32];
+        let mut n = 0;
+        n += me.note_on(&mut buf[n..], 5, 42, 0.5).unwrap();
+        n += me.control_change(&mut buf[n..], 5, 1, 0.25).unwrap();
+        n += me.pitch_bend(&mut buf[n..], 5, 0.5).unwrap();
+        n += me.channel_pressure(&mut buf[n..], 5, 1.0).unwrap();
+        buf[..n].iter().for_each(|b| mr.parse(*b));
+
+        assert_eq!(mr.note_num(), 42);
+        assert!((mr.velocity() - 0.5).abs() < 0.01);
+        assert!((mr.mod_wheel() - 0.25).abs() < 0.01);
+        assert!((mr.pitch_bend() - 0.5).abs() < 0.001);
+        assert_eq!(mr.aftertouch(), 1.0);
+
+        me.note_off(&mut buf, 5, 42, 0.0).unwrap();
+        buf[..3].iter().for_each(|b| mr.parse(*b));
+        assert!(!mr.gate());
+    }
+}
@@ -7,21 +7,34 @@
 //! A MIDI receiver is fed MIDI data in the form of sequential bytes following the MIDI protocol and converts this MIDI
 //! data into various common synthesizer control signals.
 //!
+//! The sustain pedal (CC64) and sostenuto pedal (CC66) are supported. While the sustain pedal is down, released notes
+//! keep sounding until the pedal is lifted. The sostenuto pedal does the same, but only for the notes which were held
+//! down at the moment the pedal was pressed.
+//!
+//! Besides the common controllers which have their own accessors, any CC number can be assigned to one of
+//! `MAX_CC_SLOTS` slots, either directly or with MIDI learn, and read back with `cc(slot)`. CCs 0 to 31 have 14-bit
+//! resolution when the controller also sends the matching LSB on CCs 32 to 63.
+//!
+//! RPNs and NRPNs, including data increment and decrement, can be assigned to one of `MAX_PARAMETER_SLOTS` parameter
+//! slots and read back with `parameter(slot)`. RPN 0, pitch-bend sensitivity, always sets the pitch-bend range. A
+//! parameter may also be chosen to set the VCF cutoff with 14-bit resolution, since the VCF cutoff CC has no LSB.
+//!
+//! The receiver can listen to a single channel, a set of channels given by a channel mask, or every channel in Omni
+//! mode. The Channel Mode messages for Omni On/Off, Mono On/Poly On, and Local Control are handled, the mono and local
+//! control settings are only reported for the firmware to act on.
+//!
 //! Not every part of the MIDI protocol is handled.
 
 use heapless::Vec;
+use midi_convert::midi_types::MidiMessage;
+
+use crate::midi_channel::{note_bit, notes_in, ChannelEvent, MidiChannel};
 
-use midi_convert::{
-    midi_types::{MidiMessage, Value7},
-    MidiByteStreamParser,
-};
+pub use crate::midi_channel::{Parameter, MAX_CC_SLOTS, MAX_PARAMETER_SLOTS};
 
 /// A Monophonic MIDI receiver is represented here.
 pub struct MonoMidiReceiver {
-    parser: MidiByteStreamParser,
-
-    // the MIDI channel to listen to in `[0..15]`
-    channel: u8,
+    channel: MidiChannel,
 
     // in `[0..127]`
     note_num: u8,
@@ -29,35 +42,21 @@ pub struct MonoMidiReceiver {
     // in `[0.0, 1.0]`
     velocity: f32,
 
-    // in `[-1.0, 1.0]`
-    pitch_bend: f32,
-
-    // in `[0.0, 1.0]`
-    mod_wheel: f32,
-
-    // in `[0.0, 1.0]`
-    volume: f32,
-
-    // in `[0.0, 1.0]`
-    vcf_cutoff: f32,
-
-    // in `[0.0, 1.0]`
-    vcf_resonance: f32,
-
-    // in `[0.0, 1.0]`
-    portamento_time: f32,
-
-    portamento_enabled: bool,
-    sustain_enabled: bool,
+    // in cents, added to the pitch CV
+    fine_tune: f32,
 
     gate: bool,
     rising_gate: bool,
     falling_gate: bool,
 
+    // true if the current note was reached while other notes were being held down
+    legato: bool,
+
     retrigger_mode: RetriggerMode,
     note_priority: NotePriority,
 
-    // the notes currently being held down, we choose which note is active based on the note-priority-mode
+    // the notes currently sounding, we choose which note is active based on the note-priority-mode
+    // notes which are released while a pedal is down stay here until the pedal is released
     held_down_notes: Vec<u8, HELD_DOWN_NOTE_BUFFER_LEN>,
 }
 
@@ -71,28 +70,20 @@ impl MonoMidiReceiver {
     /// The channel is clamped to `[0..15]`
     pub fn new(channel: u8) -> Self {
         Self {
-            parser: MidiByteStreamParser::new(),
-
-            channel: channel.min(15),
+            channel: MidiChannel::new(channel),
 
             note_num: 0,
 
-            pitch_bend: 0.0_f32,
-
             velocity: 0.0_f32,
-            mod_wheel: 0.0_f32,
-            volume: 0.0_f32,
-            vcf_cutoff: 0.0_f32,
-            vcf_resonance: 0.0_f32,
-            portamento_time: 0.0_f32,
 
-            portamento_enabled: true,
-            sustain_enabled: true,
+            fine_tune: 0.0_f32,
 
             gate: false,
             rising_gate: false,
             falling_gate: false,
 
+            legato: false,
+
             retrigger_mode: RetriggerMode::NoRetrigger,
             note_priority: NotePriority::Last,
 
@@ -118,66 +109,83 @@ impl MonoMidiReceiver {
     /// assert_eq!(mr.velocity(), 1.0);
     /// ```
     pub fn parse(&mut self, byte: u8) {
-        match self.parser.parse(byte) {
-            Some(MidiMessage::NoteOn(ch, note, vel)) if u8::from(ch) == self.channel => {
-                // note-on with velocity of zero is interpreted as note-off
-                if 0 == u8::from(vel) {
-                    self.handle_note_off(note.into());
-                } else {
-                    self.handle_note_on(note.into(), vel);
-                };
-            }
-            Some(MidiMessage::NoteOff(ch, note, _)) if u8::from(ch) == self.channel => {
-                self.handle_note_off(note.into());
-            }
-            Some(MidiMessage::PitchBendChange(ch, val_u14)) if u8::from(ch) == self.channel => {
-                self.pitch_bend = f32::from(val_u14);
+        let event = self.channel.parse(byte);
+        self.handle_event(event);
+    }
+
+    /// `mr.handle_message(m)` updates the internal state from MIDI message `m`, for messages which were already parsed
+    pub(crate) fn handle_message(&mut self, message: MidiMessage) {
+        let event = self.channel.handle_message(message);
+        self.handle_event(event);
+    }
+
+    /// `mr.all_notes_off()` turns every note off, including notes held by the pedals
+    pub(crate) fn all_notes_off(&mut self) {
+        let event = self.channel.all_notes_off();
+        self.handle_event(event);
+    }
+
+    /// `mr.handle_event(e)` updates the notes and gates after channel event `e`
+    fn handle_event(&mut self, event: Option<ChannelEvent>) {
+        match event {
+            Some(ChannelEvent::NoteOn(note, velocity)) => self.handle_note_on(note, velocity),
+            Some(ChannelEvent::NoteOff(note)) => self.handle_note_off(note),
+            Some(ChannelEvent::ReleaseNotes(notes)) => {
+                notes_in(notes).for_each(|n| self.handle_note_off(n))
             }
-            Some(MidiMessage::ControlChange(ch, cc, val7)) if u8::from(ch) == self.channel => {
-                match u8::from(cc) {
-                    CC_MOD_WHEEL => self.mod_wheel = value7_to_f32(val7),
-                    CC_VOLUME => self.volume = value7_to_f32(val7),
-                    CC_VCF_CUTOFF => self.vcf_cutoff = value7_to_f32(val7),
-                    CC_VCF_RESONANCE => self.vcf_resonance = value7_to_f32(val7),
-                    CC_PORTAMENTO_TIME => self.portamento_time = value7_to_f32(val7),
-                    CC_PORTAMENTO_SWITCH => {
-                        self.portamento_enabled = U7_HALF_SCALE <= u8::from(val7)
-                    }
-                    CC_SUSTAIN_SWITCH => self.sustain_enabled = U7_HALF_SCALE <= u8::from(val7),
-                    CC_ALL_CONTROLLERS_OFF => self.reset_controllers(),
-                    CC_ALL_NOTES_OFF => {
-                        self.held_down_notes.clear();
-                        self.gate = false;
-                        self.rising_gate = false;
-                        self.falling_gate = false;
-                    }
-                    _ => (), // ignore all other MIDI CC messages
-                }
+            Some(ChannelEvent::AllNotesOff) => {
+                self.held_down_notes.clear();
+                self.gate = false;
+                self.rising_gate = false;
+                self.falling_gate = false;
             }
-            _ => (), // ignore all other MIDI messages
+            None => (),
         }
     }
 
     /// `mr.handle_note_on(n, v)` updates the internal state after receiving a note-on message
-    fn handle_note_on(&mut self, note: u8, velocity: Value7) {
-        self.velocity = value7_to_f32(velocity);
+    fn handle_note_on(&mut self, note: u8, velocity: f32) {
+        self.velocity = velocity;
+
+        // the note might still be sounding because of the pedals, it is moved to the end instead of added twice
+        self.held_down_notes.retain(|n| *n != note);
 
+        let held_keys = self.channel.held_notes();
+
+        if self.held_down_notes.is_full() {
+            // make room by forgetting the oldest note which is only sounding because of the pedals, or failing that the
+            // oldest note, so that the new note is never dropped
+            let oldest = self
+                .held_down_notes
+                .iter()
+                .position(|n| held_keys & note_bit(*n) == 0)
+                .unwrap_or(0);
+            self.held_down_notes.remove(oldest);
+        }
         self.held_down_notes.push(note).ok();
 
         self.note_num = self.choose_next_note();
 
+        // only keys which are physically held down make a note legato, not notes sounding because of the pedals
+        self.legato = held_keys & !note_bit(note) != 0;
+
         self.gate = true;
         self.falling_gate = false;
 
-        if (self.retrigger_mode == RetriggerMode::AllowRetrigger)
-            | (self.held_down_notes.len() == 1)
-        {
+        if (self.retrigger_mode == RetriggerMode::AllowRetrigger) | !self.legato {
             self.rising_gate = true;
         }
     }
 
-    /// `mr.handle_note_off()` updates the internal state after receiving a note-off message
+    /// `mr.handle_note_off()` updates the internal state after a note is released
+    ///
+    /// Notes held by the pedals only arrive here once the pedals let go of them.
     fn handle_note_off(&mut self, note: u8) {
+        if !self.held_down_notes.contains(&note) {
+            // the note was forgotten to make room for newer notes while the pedals were down
+            return;
+        }
+
         // delete the note from the list of notes which are held down
         self.held_down_notes.retain(|n| *n != note);
 
@@ -188,6 +196,7 @@ impl MonoMidiReceiver {
         } else {
             // we know that there is at least one element in the vec
             self.note_num = self.choose_next_note();
+            self.legato = true;
         }
     }
 
@@ -209,10 +218,66 @@ impl MonoMidiReceiver {
 
     /// `mr.pitch_bend()` is the current MIDI pitch-bend value held by the MIDI receiver, in `[-1.0, 1.0]`
     ///
-    /// Typically a value of -1 means "bend 2 semitones down", 0 means "don't bend at all", and +1 means "bend 2
-    /// semitones up", but this behavior can be tweaked by the end user.
+    /// A value of -1 means "bend all the way down", 0 means "don't bend at all", and +1 means "bend all the way up".
+    /// See `pitch_bend_semitones()` for the bend scaled by the bend range.
     pub fn pitch_bend(&self) -> f32 {
-        self.pitch_bend
+        self.channel.pitch_bend()
+    }
+
+    /// `mr.pitch_bend_semitones()` is the current pitch bend in semitones, scaled by the pitch-bend range
+    pub fn pitch_bend_semitones(&self) -> f32 {
+        self.channel.pitch_bend_semitones()
+    }
+
+    /// `mr.set_pitch_bend_range(u, d)` sets how far a full bend goes, `u` semitones up and `d` semitones down
+    ///
+    /// The ranges are clamped to `[0.0, 127.0]`. The range defaults to 2 semitones both ways, and is also set by the
+    /// controller with RPN 0 (pitch-bend sensitivity) messages, which set both directions to the same range.
+    pub fn set_pitch_bend_range(&mut self, up: f32, down: f32) {
+        self.channel.set_pitch_bend_range(up, down);
+    }
+
+    /// `mr.pitch_bend_range()` is the pitch-bend range up and down, in semitones
+    pub fn pitch_bend_range(&self) -> (f32, f32) {
+        self.channel.pitch_bend_range()
+    }
+
+    /// `mr.set_fine_tune(c)` sets the fine-tune to `c` cents, which is added to the pitch CV
+    pub fn set_fine_tune(&mut self, cents: f32) {
+        self.fine_tune = cents;
+    }
+
+    /// `mr.fine_tune()` is the fine-tune in cents
+    pub fn fine_tune(&self) -> f32 {
+        self.fine_tune
+    }
+
+    /// `mr.pitch_cv()` is the pitch in volts at 1 volt per octave, combining the note number, bend, and fine-tune
+    ///
+    /// MIDI note zero is zero volts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::MonoMidiReceiver;
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// for b in [0x90, 24, 127] {
+    ///     mr.parse(b);
+    /// }
+    /// assert_eq!(mr.pitch_cv(), 2.0);
+    ///
+    /// // bend all the way up, 2 semitones by default
+    /// for b in [0xE0, 0x7F, 0x7F] {
+    ///     mr.parse(b);
+    /// }
+    /// assert!((mr.pitch_cv() - 26.0 / 12.0).abs() < 0.001);
+    /// ```
+    pub fn pitch_cv(&self) -> f32 {
+        let semitones = self.note_num as f32
+            + self.pitch_bend_semitones()
+            + self.fine_tune / CENTS_PER_SEMITONE;
+        semitones / SEMITONES_PER_OCTAVE
     }
 
     /// `mr.velocity()` is the current MIDI velocity value held by the MIDI receiver, in `[0.0, 1.0]`
@@ -220,39 +285,271 @@ impl MonoMidiReceiver {
         self.velocity
     }
 
+    /// `mr.aftertouch()` is the current aftertouch of the active note in `[0.0, 1.0]`
+    ///
+    /// Both channel pressure and polyphonic key pressure are handled. Only the key pressure of the active note is
+    /// used, and if the controller sends both kinds of pressure the larger one wins.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::MonoMidiReceiver;
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// for b in [0x90, 60, 127, 0xA0, 60, 127] {
+    ///     mr.parse(b);
+    /// }
+    ///
+    /// assert_eq!(mr.aftertouch(), 1.0);
+    /// ```
+    pub fn aftertouch(&self) -> f32 {
+        self.channel
+            .channel_pressure()
+            .max(self.channel.key_pressure(self.note_num))
+    }
+
     /// `mr.mod_wheel()` is the current MIDI mod-wheel value held by the MIDI receiver, in `[0.0, 1.0]`
+    ///
+    /// The value has 14-bit resolution if the controller sends the LSB.
     pub fn mod_wheel(&self) -> f32 {
-        self.mod_wheel
+        self.channel.mod_wheel()
     }
 
     /// `mr.volume()` is the current MIDI volume value held by the MIDI receiver, in `[0.0, 1.0]`
+    ///
+    /// The value has 14-bit resolution if the controller sends the LSB.
     pub fn volume(&self) -> f32 {
-        self.volume
+        self.channel.volume()
     }
 
     /// `mr.vcf_cutoff()` is the current MIDI VCF-cutoff value held by the MIDI receiver, in `[0.0, 1.0]`
+    ///
+    /// The VCF cutoff CC (CC71) has no LSB so it only has 7-bit resolution. For 14-bit resolution choose a parameter
+    /// with `set_vcf_cutoff_parameter`, the cutoff follows whichever of the CC and the parameter was received last.
     pub fn vcf_cutoff(&self) -> f32 {
-        self.vcf_cutoff
+        self.channel.vcf_cutoff()
+    }
+
+    /// `mr.set_vcf_cutoff_parameter(p)` makes RPN or NRPN `p` set the VCF cutoff with 14-bit resolution
+    ///
+    /// `None` means that only the VCF cutoff CC sets the cutoff, which is the default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::{MonoMidiReceiver, Parameter};
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// mr.set_vcf_cutoff_parameter(Some(Parameter::NonRegistered(71)));
+    ///
+    /// // select NRPN 71, then send the data entry MSB and LSB
+    /// for b in [0xB0, 0x63, 0, 0x62, 71, 0x06, 0x40, 0x26, 0x01] {
+    ///     mr.parse(b);
+    /// }
+    ///
+    /// assert_eq!(mr.vcf_cutoff(), 0x2001 as f32 / 0x3FFF as f32);
+    /// ```
+    pub fn set_vcf_cutoff_parameter(&mut self, parameter: Option<Parameter>) {
+        self.channel.set_vcf_cutoff_parameter(parameter);
+    }
+
+    /// `mr.vcf_cutoff_parameter()` is the parameter which sets the VCF cutoff, or `None` if there isn't one
+    pub fn vcf_cutoff_parameter(&self) -> Option<Parameter> {
+        self.channel.vcf_cutoff_parameter()
     }
 
     /// `mr.vcf_resonance()` is the current MIDI VCF-resonance value held by the MIDI receiver, in `[0.0, 1.0]`
     pub fn vcf_resonance(&self) -> f32 {
-        self.vcf_resonance
+        self.channel.vcf_resonance()
     }
 
     /// `mr.portamento_time()` is the current MIDI portamento-time value held by the MIDI receiver, in `[0.0, 1.0]`
+    ///
+    /// The value has 14-bit resolution if the controller sends the LSB.
     pub fn portamento_time(&self) -> f32 {
-        self.portamento_time
+        self.channel.portamento_time()
     }
 
     /// `mr.portamento_enabled()` is true if MIDI portamento is currently enabled
     pub fn portamento_enabled(&self) -> bool {
-        self.portamento_enabled
+        self.channel.portamento_enabled()
     }
 
-    /// `mr.sustain_enabled()` is true if MIDI sustain is currently enabled
+    /// `mr.sustain_enabled()` is true if the MIDI sustain pedal is currently down
     pub fn sustain_enabled(&self) -> bool {
-        self.sustain_enabled
+        self.channel.sustain_enabled()
+    }
+
+    /// `mr.sostenuto_enabled()` is true if the MIDI sostenuto pedal is currently down
+    pub fn sostenuto_enabled(&self) -> bool {
+        self.channel.sostenuto_enabled()
+    }
+
+    /// `mr.assign_cc(s, c)` assigns MIDI CC number `c` to slot `s`, so that `mr.cc(s)` follows that CC
+    ///
+    /// Slots are in `[0, MAX_CC_SLOTS)`, other slots are ignored. Firmware can give the slots names by using consts or
+    /// an enum for the slot numbers. A CC may be assigned to several slots, and assigning a CC does not stop it from
+    /// controlling its usual accessor such as `mod_wheel()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::MonoMidiReceiver;
+    ///
+    /// const WAVE_FOLD: usize = 0;
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// mr.assign_cc(WAVE_FOLD, 20);
+    /// for b in [0xB0, 20, 127] {
+    ///     mr.parse(b);
+    /// }
+    ///
+    /// assert_eq!(mr.cc(WAVE_FOLD), 1.0);
+    /// ```
+    pub fn assign_cc(&mut self, slot: usize, cc: u8) {
+        self.channel.assign_cc(slot, cc);
+    }
+
+    /// `mr.unassign_cc(s)` removes the CC assigned to slot `s`
+    pub fn unassign_cc(&mut self, slot: usize) {
+        self.channel.unassign_cc(slot);
+    }
+
+    /// `mr.assigned_cc(s)` is the CC number assigned to slot `s`, or `None` if there isn't one
+    pub fn assigned_cc(&self, slot: usize) -> Option<u8> {
+        self.channel.assigned_cc(slot)
+    }
+
+    /// `mr.learn_cc(s)` starts MIDI learn, the next CC received is assigned to slot `s`
+    ///
+    /// The CCs for data entry, RPNs, and NRPNs, and the channel mode messages, are not learned. Learning again before a
+    /// CC is received moves learning to the new slot.
+    pub fn learn_cc(&mut self, slot: usize) {
+        self.channel.learn_cc(slot);
+    }
+
+    /// `mr.cancel_learn()` stops MIDI learn without assigning a CC
+    pub fn cancel_learn(&mut self) {
+        self.channel.cancel_learn();
+    }
+
+    /// `mr.learning_slot()` is the slot waiting for MIDI learn, or `None` if MIDI learn is not active
+    pub fn learning_slot(&self) -> Option<usize> {
+        self.channel.learning_slot()
+    }
+
+    /// `mr.cc(s)` is the value of the CC assigned to slot `s`, in `[0.0, 1.0]`
+    ///
+    /// Slots without a CC assigned are always zero. CCs 0 to 31 have 14-bit resolution if their LSB is sent.
+    pub fn cc(&self, slot: usize) -> f32 {
+        self.channel.cc(slot)
+    }
+
+    /// `mr.assign_parameter(s, p)` assigns RPN or NRPN `p` to parameter slot `s`, so that `mr.parameter(s)` follows it
+    ///
+    /// Slots are in `[0, MAX_PARAMETER_SLOTS)`, other slots are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::{MonoMidiReceiver, Parameter};
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// mr.assign_parameter(0, Parameter::NonRegistered(0x0105));
+    ///
+    /// // select NRPN 0x0105 and set it to half scale
+    /// for b in [0xB0, 0x63, 0x02, 0x62, 0x05, 0x06, 0x40, 0x26, 0x00] {
+    ///     mr.parse(b);
+    /// }
+    ///
+    /// assert!((mr.parameter(0) - 0.5).abs() < 0.001);
+    /// ```
+    pub fn assign_parameter(&mut self, slot: usize, parameter: Parameter) {
+        self.channel.assign_parameter(slot, parameter);
+    }
+
+    /// `mr.unassign_parameter(s)` removes the parameter assigned to parameter slot `s`
+    pub fn unassign_parameter(&mut self, slot: usize) {
+        self.channel.unassign_parameter(slot);
+    }
+
+    /// `mr.assigned_parameter(s)` is the parameter assigned to parameter slot `s`, or `None` if there isn't one
+    pub fn assigned_parameter(&self, slot: usize) -> Option<Parameter> {
+        self.channel.assigned_parameter(slot)
+    }
+
+    /// `mr.parameter(s)` is the 14-bit value of the parameter assigned to parameter slot `s`, in `[0.0, 1.0]`
+    ///
+    /// Slots without a parameter assigned are always zero.
+    pub fn parameter(&self, slot: usize) -> f32 {
+        self.channel.parameter(slot)
+    }
+
+    /// `mr.set_omni(o)` turns Omni mode on if `o` is true, else off
+    ///
+    /// In Omni mode messages on every channel are handled, regardless of the channel mask. All notes are turned off,
+    /// the same as when the controller sends an Omni On or Omni Off message.
+    pub fn set_omni(&mut self, omni: bool) {
+        let event = self.channel.set_omni(omni);
+        self.handle_event(event);
+    }
+
+    /// `mr.omni()` is true if Omni mode is on
+    pub fn omni(&self) -> bool {
+        self.channel.omni()
+    }
+
+    /// `mr.set_channel_mask(m)` listens to the zero-based MIDI channels set in mask `m` and turns all notes off
+    ///
+    /// Bit 0 is channel 0, bit 1 is channel 1, and so on. `MonoMidiReceiver::new(c)` listens to channel `c` only.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::mono_midi_receiver::MonoMidiReceiver;
+    ///
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// mr.set_channel_mask(1 << 0 | 1 << 9);
+    /// for b in [0x99, 42, 127] {
+    ///     mr.parse(b);
+    /// }
+    ///
+    /// assert_eq!(mr.note_num(), 42);
+    /// ```
+    pub fn set_channel_mask(&mut self, mask: u16) {
+        let event = self.channel.set_channel_mask(mask);
+        self.handle_event(event);
+    }
+
+    /// `mr.channel_mask()` is the mask of MIDI channels being listened to
+    pub fn channel_mask(&self) -> u16 {
+        self.channel.channel_mask()
+    }
+
+    /// `mr.mono_mode()` is true if the controller asked for mono mode with a Mono On message, and false after Poly On
+    ///
+    /// This receiver is always monophonic, firmware which can also play polyphonically may switch to a polyphonic
+    /// receiver when this is false.
+    pub fn mono_mode(&self) -> bool {
+        self.channel.mono_mode()
+    }
+
+    /// `mr.local_control()` is true unless the controller turned Local Control off
+    ///
+    /// When local control is off, an instrument's own keyboard should only send MIDI and not play its sound engine.
+    pub fn local_control(&self) -> bool {
+        self.channel.local_control()
+    }
+
+    /// `mr.legato()` is true if the current note was reached while other notes were being held down
+    ///
+    /// This is the case when a new note is played before the previous one is released, or when a note is released and
+    /// the receiver falls back to another note which is still held down. Legato note changes are typically used to
+    /// decide when to apply "fingered" portamento.
+    ///
+    /// Notes which are only sounding because of the sustain or sostenuto pedal don't make a new note legato.
+    pub fn legato(&self) -> bool {
+        self.legato
     }
 
     /// `mr.gate()` is true if any MIDI notes are currently being played
@@ -294,18 +591,6 @@ impl MonoMidiReceiver {
     pub fn set_note_priority(&mut self, priority: NotePriority) {
         self.note_priority = priority;
     }
-
-    /// `mr.reset_controllers()` resets all implemented MIDI controllers to their default values
-    fn reset_controllers(&mut self) {
-        self.pitch_bend = 0.0_f32;
-        self.mod_wheel = 0.0_f32;
-        self.volume = 0.0_f32;
-        self.vcf_cutoff = 0.0_f32;
-        self.vcf_resonance = 0.0_f32;
-        self.portamento_time = 0.0_f32;
-        self.portamento_enabled = true;
-        self.sustain_enabled = true;
-    }
 }
 
 /// Retrigger mode is represented here
@@ -337,24 +622,8 @@ pub enum NotePriority {
     Low,
 }
 
-///`value7_to_f32(v)` is the Value7 converted to f32 in `[0.0, 1.0]`
-fn value7_to_f32(val7: Value7) -> f32 {
-    u8::from(val7) as f32 / 127.0_f32
-}
-
-// Common MIDI CC names
-const CC_MOD_WHEEL: u8 = 0x01;
-const CC_VOLUME: u8 = 0x07;
-const CC_VCF_CUTOFF: u8 = 0x47;
-const CC_VCF_RESONANCE: u8 = 0x4A;
-const CC_SUSTAIN_SWITCH: u8 = 0x40;
-const CC_PORTAMENTO_SWITCH: u8 = 0x41;
-const CC_PORTAMENTO_TIME: u8 = 0x05;
-const CC_ALL_CONTROLLERS_OFF: u8 = 0x79;
-const CC_ALL_NOTES_OFF: u8 = 0x7B;
-
-// for MIDI CC used as switches values below half scale are considered false and values at-least half scale are true
-const U7_HALF_SCALE: u8 = 1 << 6;
+const SEMITONES_PER_OCTAVE: f32 = 12.0_f32;
+const CENTS_PER_SEMITONE: f32 = 100.0_f32;
 
 /// The maximum number of held down MIDI notes we can remember
 ///
@@ -364,6 +633,10 @@ const HELD_DOWN_NOTE_BUFFER_LEN: usize = 32;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::midi_channel::{
+        CC_ALL_NOTES_OFF, CC_DATA_ENTRY_MSB, CC_LOCAL_CONTROL, CC_MONO_ON, CC_OMNI_OFF, CC_OMNI_ON,
+        CC_RPN_LSB, CC_RPN_MSB, CC_SOSTENUTO_SWITCH, CC_SUSTAIN_SWITCH,
+    };
 
     #[test]
     fn should_listen_on_correct_channel() {
@@ -600,6 +873,229 @@ mod tests {
         assert_eq!(mr.note_num(), 5);
     }
 
+    #[test]
+    fn overlapping_notes_are_legato() {
+        let mut mr = MonoMidiReceiver::new(1);
+        mr.parse(0x91);
+        mr.parse(42);
+        mr.parse(1);
+        // the first note is not legato, nothing else was held down
+        assert!(!mr.legato());
+
+        mr.parse(43);
+        mr.parse(1);
+        assert!(mr.legato());
+
+        // falling back to the held down note is legato too
+        mr.parse(0x81);
+        mr.parse(43);
+        mr.parse(0);
+        assert_eq!(mr.note_num(), 42);
+        assert!(mr.legato());
+
+        // lifting all notes and playing a new one is not legato
+        mr.parse(42);
+        mr.parse(0);
+        mr.parse(0x91);
+        mr.parse(44);
+        mr.parse(1);
+        assert!(!mr.legato());
+    }
+
+    fn pedal(mr: &mut MonoMidiReceiver, cc: u8, down: bool) {
+        mr.parse(0xB1);
+        mr.parse(cc);
+        mr.parse(if down { 127 } else { 0 });
+    }
+
+    #[test]
+    fn sustain_pedal_is_up_by_default() {
+        let mr = MonoMidiReceiver::new(1);
+        assert!(!mr.sustain_enabled());
+        assert!(!mr.sostenuto_enabled());
+    }
+
+    #[test]
+    fn sustain_pedal_holds_the_gate_until_lifted() {
+        let mut mr = MonoMidiReceiver::new(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+
+        mr.parse(0x91);
+        mr.parse(42);
+        mr.parse(1);
+        mr.parse(0x81);
+        mr.parse(42);
+        mr.parse(0);
+
+        assert!(mr.gate());
+        assert!(!mr.falling_gate());
+
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert!(!mr.gate());
+        assert!(mr.falling_gate());
+    }
+
+    #[test]
+    fn lifting_sustain_falls_back_to_the_physically_held_note() {
+        let mut mr = MonoMidiReceiver::new(1);
+        mr.parse(0x91);
+        mr.parse(40);
+        mr.parse(1);
+
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+        mr.parse(0x91);
+        mr.parse(50);
+        mr.parse(1);
+        mr.parse(0x81);
+        mr.parse(50);
+        mr.parse(0);
+
+        // note 50 keeps sounding while the pedal is down
+        assert_eq!(mr.note_num(), 50);
+
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert_eq!(mr.note_num(), 40);
+        assert!(mr.gate());
+    }
+
+    #[test]
+    fn restriking_a_sustained_note_does_not_duplicate_it() {
+        let mut mr = MonoMidiReceiver::new(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+
+        for _ in 0..3 {
+            mr.parse(0x91);
+            mr.parse(42);
+            mr.parse(1);
+            mr.parse(0x81);
+            mr.parse(42);
+            mr.parse(0);
+        }
+        assert_eq!(mr.held_down_notes.len(), 1);
+
+        // the restruck note is held down again, lifting the pedal doesn't release it
+        mr.parse(0x91);
+        mr.parse(42);
+        mr.parse(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert!(mr.gate());
+    }
+
+    #[test]
+    fn newest_note_sounds_after_a_sustained_glissando() {
+        let mut mr = MonoMidiReceiver::new(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+
+        for note in 20..(20 + HELD_DOWN_NOTE_BUFFER_LEN as u8 + 10) {
+            mr.parse(0x91);
+            mr.parse(note);
+            mr.parse(1);
+            mr.parse(0x81);
+            mr.parse(note);
+            mr.parse(0);
+            assert_eq!(mr.note_num(), note);
+        }
+
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert!(!mr.gate());
+        assert!(mr.falling_gate());
+    }
+
+    #[test]
+    fn notes_sustained_by_the_pedal_are_not_legato() {
+        let mut mr = MonoMidiReceiver::new(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+
+        mr.parse(0x91);
+        mr.parse(40);
+        mr.parse(1);
+        mr.parse(0x81);
+        mr.parse(40);
+        mr.parse(0);
+        assert!(mr.rising_gate());
+
+        // a detached note while the pedal holds the first one
+        mr.parse(0x91);
+        mr.parse(50);
+        mr.parse(1);
+        assert!(!mr.legato());
+        assert!(mr.rising_gate());
+
+        // an overlapping note is legato
+        mr.parse(60);
+        mr.parse(1);
+        assert!(mr.legato());
+        assert!(!mr.rising_gate());
+    }
+
+    #[test]
+    fn sostenuto_only_holds_notes_down_when_pressed() {
+        let mut mr = MonoMidiReceiver::new(1);
+        mr.parse(0x91);
+        mr.parse(40);
+        mr.parse(1);
+
+        pedal(&mut mr, CC_SOSTENUTO_SWITCH, true);
+
+        // note 50 is played after the pedal went down, so it isn't held
+        mr.parse(0x91);
+        mr.parse(50);
+        mr.parse(1);
+        mr.parse(0x81);
+        mr.parse(50);
+        mr.parse(0);
+        assert_eq!(mr.note_num(), 40);
+
+        // note 40 is held by the pedal
+        mr.parse(40);
+        mr.parse(0);
+        assert!(mr.gate());
+
+        pedal(&mut mr, CC_SOSTENUTO_SWITCH, false);
+        assert!(!mr.gate());
+        assert!(mr.falling_gate());
+    }
+
+    #[test]
+    fn notes_held_by_both_pedals_wait_for_both() {
+        let mut mr = MonoMidiReceiver::new(1);
+        mr.parse(0x91);
+        mr.parse(40);
+        mr.parse(1);
+        pedal(&mut mr, CC_SOSTENUTO_SWITCH, true);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+        mr.parse(0x81);
+        mr.parse(40);
+        mr.parse(0);
+
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert!(mr.gate());
+        pedal(&mut mr, CC_SOSTENUTO_SWITCH, false);
+        assert!(!mr.gate());
+    }
+
+    #[test]
+    fn all_notes_off_clears_sustained_notes() {
+        let mut mr = MonoMidiReceiver::new(1);
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, true);
+        mr.parse(0x91);
+        mr.parse(42);
+        mr.parse(1);
+        mr.parse(0x81);
+        mr.parse(42);
+        mr.parse(0);
+
+        mr.parse(0xB1);
+        mr.parse(CC_ALL_NOTES_OFF);
+        mr.parse(0);
+        assert!(!mr.gate());
+
+        // lifting the pedal afterwards doesn't cause another falling gate
+        mr.falling_gate();
+        pedal(&mut mr, CC_SUSTAIN_SWITCH, false);
+        assert!(!mr.falling_gate());
+    }
+
     #[test]
     fn note_off_keeps_the_last_note() {
         let mut mr = MonoMidiReceiver::new(1);
@@ -614,4 +1110,219 @@ mod tests {
         // but it's still retained as the last valid note
         assert_eq!(mr.note_num(), 42);
     }
+
+    #[test]
+    fn pitch_cv_combines_note_bend_and_fine_tune() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.set_pitch_bend_range(12.0, 5.0);
+        mr.set_fine_tune(50.0);
+        for b in [0x90, 60, 127, 0xE0, 0x00, 0x00] {
+            mr.parse(b);
+        }
+        assert!((mr.pitch_cv() - (60.0 - 5.0 + 0.5) / 12.0).abs() < 0.001);
+
+        mr.parse(0xE0);
+        mr.parse(0x7F);
+        mr.parse(0x7F);
+        assert!((mr.pitch_cv() - (60.0 + 12.0 + 0.5) / 12.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn controller_can_set_the_bend_range() {
+        let mut mr = MonoMidiReceiver::new(0);
+        for b in [0xB0, CC_RPN_MSB, 0, CC_RPN_LSB, 0, CC_DATA_ENTRY_MSB, 24] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.pitch_bend_range(), (24.0, 24.0));
+    }
+
+    #[test]
+    fn bend_range_is_clamped() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.set_pitch_bend_range(-1.0, 200.0);
+        assert_eq!(mr.pitch_bend_range(), (0.0, 127.0));
+    }
+
+    #[test]
+    fn learned_cc_can_be_read_from_its_slot() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.learn_cc(2);
+        assert_eq!(mr.learning_slot(), Some(2));
+
+        // CCs on other channels are not learned
+        for b in [0xB1, 30, 127, 0xB0, 31, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.learning_slot(), None);
+        assert_eq!(mr.assigned_cc(2), Some(31));
+        assert_eq!(mr.cc(2), 1.0);
+
+        // running status
+        mr.parse(31);
+        mr.parse(0);
+        assert_eq!(mr.cc(2), 0.0);
+    }
+
+    #[test]
+    fn cancelled_learn_assigns_nothing() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.learn_cc(0);
+        mr.cancel_learn();
+        for b in [0xB0, 31, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.assigned_cc(0), None);
+    }
+
+    #[test]
+    fn mapped_cc_still_drives_its_controller() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.assign_cc(0, 0x01);
+        for b in [0xB0, 0x01, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.cc(0), 1.0);
+        assert_eq!(mr.mod_wheel(), 1.0);
+    }
+
+    #[test]
+    fn fine_cc_gives_full_resolution() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.assign_cc(0, 16);
+        for b in [0xB0, 16, 0x40, 48, 0x01] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.cc(0), 0x2001 as f32 / 16383.0);
+    }
+
+    #[test]
+    fn cutoff_parameter_gives_finer_resolution_than_the_cc() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.set_vcf_cutoff_parameter(Some(Parameter::NonRegistered(0x0047)));
+        assert_eq!(
+            mr.vcf_cutoff_parameter(),
+            Some(Parameter::NonRegistered(0x47))
+        );
+
+        // NRPN 0x47, MSB then LSB, then one data increment
+        for b in [
+            0xB0, 0x63, 0x00, 0x62, 0x47, 0x06, 0x40, 0x26, 0x00, 0x60, 0x00,
+        ] {
+            mr.parse(b);
+        }
+        let fine = mr.vcf_cutoff();
+        assert_eq!(fine, 0x2001 as f32 / 16383.0);
+        assert!(0.0 < fine - 0x2000 as f32 / 16383.0);
+        assert!(fine - 0x2000 as f32 / 16383.0 < 1.0 / 127.0);
+
+        // the CC still works, and full scale is still full scale
+        for b in [0xB0, 0x47, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.vcf_cutoff(), 1.0);
+    }
+
+    #[test]
+    fn rpn_increment_reaches_assigned_slot() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.assign_parameter(0, Parameter::Registered(0x0005));
+        for b in [0xB0, 0x65, 0x00, 0x64, 0x05, 0x60, 0x00] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.parameter(0), 1.0 / 16383.0);
+        assert_eq!(mr.assigned_parameter(0), Some(Parameter::Registered(5)));
+
+        mr.unassign_parameter(0);
+        assert_eq!(mr.parameter(0), 0.0);
+    }
+
+    #[test]
+    fn channel_pressure_is_aftertouch() {
+        let mut mr = MonoMidiReceiver::new(0);
+        for b in [0x90, 60, 127, 0xD0, 64] {
+            mr.parse(b);
+        }
+        assert!((mr.aftertouch() - 64.0 / 127.0).abs() < 0.000_1);
+
+        mr.parse(0);
+        assert_eq!(mr.aftertouch(), 0.0);
+    }
+
+    #[test]
+    fn key_pressure_follows_the_active_note() {
+        let mut mr = MonoMidiReceiver::new(0);
+        for b in [0x90, 60, 127, 0xA0, 60, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.aftertouch(), 1.0);
+
+        // the new note has no pressure yet, pressure on other keys is ignored
+        for b in [0x90, 64, 127, 0xA0, 60, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.aftertouch(), 0.0);
+
+        // releasing the new note falls back to the old note and its pressure
+        for b in [0x80, 64, 0] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.aftertouch(), 1.0);
+    }
+
+    #[test]
+    fn omni_hears_every_channel() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.set_omni(true);
+        for b in [0x95, 42, 127] {
+            mr.parse(b);
+        }
+        assert!(mr.gate());
+        assert_eq!(mr.note_num(), 42);
+
+        // turning omni off stops the note, since its note-off would never be heard
+        mr.set_omni(false);
+        assert!(!mr.gate());
+    }
+
+    #[test]
+    fn omni_off_message_turns_notes_off() {
+        let mut mr = MonoMidiReceiver::new(0);
+        for b in [0x90, 42, 127, 0xB0, CC_OMNI_ON, 0, 0x93, 43, 127] {
+            mr.parse(b);
+        }
+        assert!(mr.omni());
+        assert_eq!(mr.note_num(), 43);
+
+        for b in [0xB0, CC_OMNI_OFF, 0] {
+            mr.parse(b);
+        }
+        assert!(!mr.omni());
+        assert!(!mr.gate());
+    }
+
+    #[test]
+    fn channel_mask_listens_to_several_channels() {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.set_channel_mask(0b0110);
+        assert_eq!(mr.channel_mask(), 0b0110);
+        for b in [0x90, 40, 127] {
+            mr.parse(b);
+        }
+        assert!(!mr.gate());
+        for b in [0x92, 41, 127] {
+            mr.parse(b);
+        }
+        assert_eq!(mr.note_num(), 41);
+    }
+
+    #[test]
+    fn mode_messages_are_reported() {
+        let mut mr = MonoMidiReceiver::new(0);
+        for b in [0x90, 40, 127, 0xB0, CC_MONO_ON, 1, CC_LOCAL_CONTROL, 0] {
+            mr.parse(b);
+        }
+        assert!(mr.mono_mode());
+        assert!(!mr.local_control());
+        assert!(!mr.gate());
+    }
 }
@@ -0,0 +1,535 @@
+//! Polyphonic MIDI Receiver
+//!
+//! Polyphonic means that several notes may be active at a time, each one played by its own voice.
+//!
+//! This MIDI receiver can be used to control paraphonic and polyphonic synthesizers. It is fed MIDI data in the form
+//! of sequential bytes following the MIDI protocol, the same as the monophonic MIDI receiver, and assigns incoming
+//! notes to a fixed number of voices. Each voice has its own note number, velocity, and gate signals.
+//!
+//! When a new note arrives and every voice is busy, a voice is stolen from one of the notes which is already playing.
+//! Which voice is used for new notes, and which voice is stolen, depends on the voice allocation strategy.
+//!
+//! The controllers such as pitch bend and the mod wheel, and the sustain and sostenuto pedals, are shared by all of
+//! the voices.
+
+use core::cmp::Reverse;
+
+use crate::midi_channel::{notes_in, ChannelEvent, MidiChannel};
+
+/// A Polyphonic MIDI receiver with `VOICES` voices is represented here.
+pub struct PolyMidiReceiver<const VOICES: usize> {
+    channel: MidiChannel,
+
+    voices: [Voice; VOICES],
+
+    allocation: VoiceAllocation,
+
+    // the voice to try first for the next note when allocating round-robin
+    next_voice: usize,
+
+    // counts note events, used to tell which voice was played or released longest ago
+    clock: u32,
+}
+
+/// The state of a single voice is represented here
+#[derive(Clone, Copy)]
+struct Voice {
+    // in `[0..127]`
+    note_num: u8,
+
+    // in `[0.0, 1.0]`
+    velocity: f32,
+
+    gate: bool,
+    rising_gate: bool,
+    falling_gate: bool,
+
+    // the clock at the last note-on and note-off of this voice
+    on_time: u32,
+    off_time: u32,
+}
+
+impl Voice {
+    const fn new() -> Self {
+        Self {
+            note_num: 0,
+            velocity: 0.0_f32,
+            gate: false,
+            rising_gate: false,
+            falling_gate: false,
+            on_time: 0,
+            off_time: 0,
+        }
+    }
+}
+
+impl<const VOICES: usize> PolyMidiReceiver<VOICES> {
+    /// `PolyMidiReceiver::new(c)` is a new Polyphonic MIDI receiver which accepts messages on MIDI channel `c`
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The zero-based MIDI channel to listen to in `[0..15]`. All other MIDI channels are ignored.
+    ///
+    /// The channel is clamped to `[0..15]`. Voices are allocated round-robin by default.
+    pub fn new(channel: u8) -> Self {
+        Self {
+            channel: MidiChannel::new(channel),
+            voices: [Voice::new(); VOICES],
+            allocation: VoiceAllocation::RoundRobin,
+            next_voice: 0,
+            clock: 0,
+        }
+    }
+
+    /// `pr.parse(b)` parses incoming MIDI data in the form of sequential bytes `b` and updates its internal state
+    ///
+    /// It is expected to call this function every time a new MIDI byte is received.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use synth_utils::poly_midi_receiver::PolyMidiReceiver;
+    ///
+    /// let mut pr = PolyMidiReceiver::<4>::new(0);
+    /// for b in [0x90, 60, 127, 64, 127] {
+    ///     pr.parse(b);
+    /// }
+    ///
+    /// assert_eq!(pr.note_num(0), 60);
+    /// assert_eq!(pr.note_num(1), 64);
+    /// assert!(pr.gate(1));
+    /// assert!(!pr.gate(2));
+    /// ```
+    pub fn parse(&mut self, byte: u8) {
+        match self.channel.parse(byte) {
+            Some(ChannelEvent::NoteOn(note, velocity)) => self.handle_note_on(note, velocity),
+            Some(ChannelEvent::NoteOff(note)) => self.handle_note_off(note),
+            Some(ChannelEvent::ReleaseNotes(notes)) => {
+                notes_in(notes).for_each(|n| self.handle_note_off(n))
+            }
+            Some(ChannelEvent::AllNotesOff) => {
+                for voice in self.voices.iter_mut().filter(|v| v.gate) {
+                    voice.gate = false;
+                    voice.rising_gate = false;
+                    voice.falling_gate = true;
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// `pr.handle_note_on(n, v)` assigns note `n` with velocity `v` to a voice
+    fn handle_note_on(&mut self, note: u8, velocity: f32) {
+        self.clock = self.clock.wrapping_add(1);
+
+        // a note which is still sounding is played again on the same voice
+        let v = match self
+            .voices
+            .iter()
+            .position(|v| v.gate && v.note_num == note)
+        {
+            Some(v) => v,
+            None => match self.free_voice(note).or_else(|| self.voice_to_steal()) {
+                Some(v) => v,
+                None => return, // there are no voices at all
+            },
+        };
+
+        let clock = self.clock;
+        let voice = &mut self.voices[v];
+        voice.note_num = note;
+        voice.velocity = velocity;
+        voice.gate = true;
+        voice.rising_gate = true;
+        voice.falling_gate = false;
+        voice.on_time = clock;
+
+        self.next_voice = (v + 1) % VOICES;
+    }
+
+    /// `pr.handle_note_off(n)` releases the voice playing note `n`, if there is one
+    ///
+    /// There might not be a voice playing the note if it was stolen.
+    fn handle_note_off(&mut self, note: u8) {
+        self.clock = self.clock.wrapping_add(1);
+
+        let clock = self.clock;
+        if let Some(voice) = self
+            .voices
+            .iter_mut()
+            .find(|v| v.gate && v.note_num == note)
+        {
+            voice.gate = false;
+            voice.rising_gate = false;
+            voice.falling_gate = true;
+            voice.off_time = clock;
+        }
+    }
+
+    /// `pr.free_voice(n)` is the voice to play note `n` on out of the voices which are not playing, if there is one
+    fn free_voice(&self, note: u8) -> Option<usize> {
+        let mut free = (0..VOICES).filter(|v| !self.voices[*v].gate);
+
+        match self.allocation {
+            VoiceAllocation::RoundRobin => (0..VOICES)
+                .map(|i| (self.next_voice + i) % VOICES)
+                .find(|v| !self.voices[*v].gate),
+            VoiceAllocation::ReuseSameNote => free
+                .clone()
+                .find(|v| self.voices[*v].note_num == note)
+                .or_else(|| self.released_longest_ago(free)),
+            _ => self.released_longest_ago(&mut free),
+        }
+    }
+
+    /// `pr.released_longest_ago(vs)` is the voice out of `vs` which was released longest ago
+    ///
+    /// Letting recently released voices finish their release keeps the envelopes from being cut short. Ties go to the
+    /// lowest numbered voice.
+    fn released_longest_ago(&self, voices: impl Iterator<Item = usize>) -> Option<usize> {
+        voices.min_by_key(|v| Reverse(self.clock.wrapping_sub(self.voices[*v].off_time)))
+    }
+
+    /// `pr.voice_to_steal()` is the voice to cut off when a new note arrives and every voice is playing
+    fn voice_to_steal(&self) -> Option<usize> {
+        let voices = 0..VOICES;
+        match self.allocation {
+            VoiceAllocation::RoundRobin => (VOICES != 0).then_some(self.next_voice),
+            VoiceAllocation::ReuseSameNote | VoiceAllocation::StealOldest => {
+                voices.max_by_key(|v| self.clock.wrapping_sub(self.voices[*v].on_time))
+            }
+            VoiceAllocation::StealQuietest => voices.min_by(|a, b| {
+                self.voices[*a]
+                    .velocity
+                    .total_cmp(&self.voices[*b].velocity)
+            }),
+            VoiceAllocation::StealLowest => voices.min_by_key(|v| self.voices[*v].note_num),
+            VoiceAllocation::StealHighest => voices.max_by_key(|v| self.voices[*v].note_num),
+        }
+    }
+
+    /// `pr.set_voice_allocation(a)` sets the voice allocation strategy to `a`
+    pub fn set_voice_allocation(&mut self, allocation: VoiceAllocation) {
+        self.allocation = allocation;
+    }
+
+    /// `pr.voice_allocation()` is the current voice allocation strategy
+    pub fn voice_allocation(&self) -> VoiceAllocation {
+        self.allocation
+    }
+
+    /// `pr.voices()` is the number of voices, `VOICES`
+    pub fn voices(&self) -> usize {
+        VOICES
+    }
+
+    /// `pr.note_num(v)` is the MIDI note number of voice `v`, or zero if there is no such voice
+    ///
+    /// Voices keep their last note number after they are released.
+    pub fn note_num(&self, voice: usize) -> u8 {
+        self.voices.get(voice).map_or(0, |v| v.note_num)
+    }
+
+    /// `pr.velocity(v)` is the MIDI velocity of voice `v` in `[0.0, 1.0]`, or zero if there is no such voice
+    pub fn velocity(&self, voice: usize) -> f32 {
+        self.voices.get(voice).map_or(0.0_f32, |v| v.velocity)
+    }
+
+    /// `pr.gate(v)` is true if voice `v` is currently playing a note
+    pub fn gate(&self, voice: usize) -> bool {
+        matches!(self.voices.get(voice), Some(v) if v.gate)
+    }
+
+    /// `pr.rising_gate(v)` is true if a new note has been triggered on voice `v`. Self clearing.
+    ///
+    /// A rising gate is triggered when a voice is stolen, even though the gate never went low.
+    pub fn rising_gate(&mut self, voice: usize) -> bool {
+        match self.voices.get_mut(voice) {
+            Some(v) if v.rising_gate => {
+                v.rising_gate = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `pr.falling_gate(v)` is true if the note on voice `v` has been released. Self clearing.
+    pub fn falling_gate(&mut self, voice: usize) -> bool {
+        match self.voices.get_mut(voice) {
+            Some(v) if v.falling_gate => {
+                v.falling_gate = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `pr.pitch_bend()` is the current MIDI pitch-bend value held by the MIDI receiver, in `[-1.0, 1.0]`
+    pub fn pitch_bend(&self) -> f32 {
+        self.channel.pitch_bend()
+    }
+
+    /// `pr.mod_wheel()` is the current MIDI mod-wheel value held by the MIDI receiver, in `[0.0, 1.0]`
+    pub fn mod_wheel(&self) -> f32 {
+        self.channel.mod_wheel()
+    }
+
+    /// `pr.volume()` is the current MIDI volume value held by the MIDI receiver, in `[0.0, 1.0]`
+    pub fn volume(&self) -> f32 {
+        self.channel.volume()
+    }
+
+    /// `pr.vcf_cutoff()` is the current MIDI VCF-cutoff value held by the MIDI receiver, in `[0.0, 1.0]`
+    pub fn vcf_cutoff(&self) -> f32 {
+        self.channel.vcf_cutoff()
+    }
+
+    /// `pr.vcf_resonance()` is the current MIDI VCF-resonance value held by the MIDI receiver, in `[0.0, 1.0]`
+    pub fn vcf_resonance(&self) -> f32 {
+        self.channel.vcf_resonance()
+    }
+
+    /// `pr.portamento_time()` is the current MIDI portamento-time value held by the MIDI receiver, in `[0.0, 1.0]`
+    pub fn portamento_time(&self) -> f32 {
+        self.channel.portamento_time()
+    }
+
+    /// `pr.portamento_enabled()` is true if MIDI portamento is currently enabled
+    pub fn portamento_enabled(&self) -> bool {
+        self.channel.portamento_enabled()
+    }
+
+    /// `pr.sustain_enabled()` is true if the MIDI sustain pedal is currently down
+    pub fn sustain_enabled(&self) -> bool {
+        self.channel.sustain_enabled()
+    }
+
+    /// `pr.sostenuto_enabled()` is true if the MIDI sostenuto pedal is currently down
+    pub fn sostenuto_enabled(&self) -> bool {
+        self.channel.sostenuto_enabled()
+    }
+}
+
+/// Voice allocation strategies are represented here
+///
+/// A free voice is one which is not currently playing a note.
+///
+/// - `RoundRobin` cycles through the voices in order, using the next free voice after the one played last. When every
+///   voice is playing, the next voice in order is stolen.
+///
+/// - `ReuseSameNote` plays a note on a free voice which last played the same note if there is one, so that voices
+///   which differ slightly keep their character for each note. When every voice is playing, the oldest note is stolen.
+///
+/// - `StealOldest` steals the voice whose note was played longest ago
+///
+/// - `StealQuietest` steals the voice whose note was played with the lowest velocity
+///
+/// - `StealLowest` steals the voice playing the lowest note
+///
+/// - `StealHighest` steals the voice playing the highest note
+///
+/// All strategies other than `RoundRobin` and `ReuseSameNote` use the free voice which was released longest ago.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceAllocation {
+    RoundRobin,
+    ReuseSameNote,
+    StealOldest,
+    StealQuietest,
+    StealLowest,
+    StealHighest,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_channel::{CC_ALL_NOTES_OFF, CC_SUSTAIN_SWITCH};
+
+    fn parse_all<const VOICES: usize>(pr: &mut PolyMidiReceiver<VOICES>, bytes: &[u8]) {
+        bytes.iter().for_each(|b| pr.parse(*b));
+    }
+
+    fn note_on<const VOICES: usize>(pr: &mut PolyMidiReceiver<VOICES>, note: u8, vel: u8) {
+        parse_all(pr, &[0x90, note, vel]);
+    }
+
+    fn note_off<const VOICES: usize>(pr: &mut PolyMidiReceiver<VOICES>, note: u8) {
+        parse_all(pr, &[0x80, note, 0]);
+    }
+
+    fn notes<const VOICES: usize>(pr: &PolyMidiReceiver<VOICES>) -> [u8; VOICES] {
+        let mut notes = [0; VOICES];
+        for (v, n) in notes.iter_mut().enumerate() {
+            *n = pr.note_num(v);
+        }
+        notes
+    }
+
+    #[test]
+    fn notes_are_spread_across_voices() {
+        let mut pr = PolyMidiReceiver::<3>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 64, 64);
+        assert_eq!(notes(&pr), [60, 64, 0]);
+        assert!(pr.gate(0) && pr.gate(1) && !pr.gate(2));
+        assert_eq!(pr.velocity(0), 1.0);
+        assert!((pr.velocity(1) - 64.0 / 127.0).abs() < 0.000_1);
+    }
+
+    #[test]
+    fn note_off_releases_its_voice() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 64, 127);
+        assert!(pr.rising_gate(1));
+        assert!(!pr.rising_gate(1));
+
+        note_off(&mut pr, 64);
+        assert!(pr.gate(0));
+        assert!(!pr.gate(1));
+        assert!(pr.falling_gate(1));
+        assert!(!pr.falling_gate(1));
+        assert!(!pr.falling_gate(0));
+    }
+
+    #[test]
+    fn replaying_a_sounding_note_reuses_its_voice() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        note_on(&mut pr, 60, 127);
+        pr.rising_gate(0);
+        note_on(&mut pr, 60, 127);
+        assert!(pr.rising_gate(0));
+        assert!(!pr.gate(1));
+    }
+
+    #[test]
+    fn round_robin_cycles_through_free_voices() {
+        let mut pr = PolyMidiReceiver::<3>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_off(&mut pr, 60);
+        note_on(&mut pr, 62, 127);
+        note_off(&mut pr, 62);
+        note_on(&mut pr, 64, 127);
+        note_off(&mut pr, 64);
+        note_on(&mut pr, 65, 127);
+        assert_eq!(notes(&pr), [65, 62, 64]);
+    }
+
+    #[test]
+    fn round_robin_steals_the_next_voice() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 62, 127);
+        note_on(&mut pr, 64, 127);
+        note_on(&mut pr, 65, 127);
+        assert_eq!(notes(&pr), [64, 65]);
+        assert!(pr.gate(0) && pr.gate(1));
+    }
+
+    #[test]
+    fn reuse_same_note_prefers_the_voice_which_played_it() {
+        let mut pr = PolyMidiReceiver::<3>::new(0);
+        pr.set_voice_allocation(VoiceAllocation::ReuseSameNote);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 62, 127);
+        note_on(&mut pr, 64, 127);
+        parse_all(&mut pr, &[0x80, 60, 0, 62, 0, 64, 0]);
+
+        note_on(&mut pr, 62, 127);
+        assert!(pr.gate(1));
+        assert!(!pr.gate(0) && !pr.gate(2));
+
+        // a note which has not been played before uses the voice released longest ago
+        note_on(&mut pr, 67, 127);
+        assert_eq!(notes(&pr), [67, 62, 64]);
+    }
+
+    #[test]
+    fn steal_oldest() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        pr.set_voice_allocation(VoiceAllocation::StealOldest);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 62, 127);
+        note_on(&mut pr, 64, 127);
+        assert_eq!(notes(&pr), [64, 62]);
+        note_on(&mut pr, 65, 127);
+        assert_eq!(notes(&pr), [64, 65]);
+    }
+
+    #[test]
+    fn steal_quietest() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        pr.set_voice_allocation(VoiceAllocation::StealQuietest);
+        note_on(&mut pr, 60, 100);
+        note_on(&mut pr, 62, 20);
+        note_on(&mut pr, 64, 127);
+        assert_eq!(notes(&pr), [60, 64]);
+    }
+
+    #[test]
+    fn steal_lowest_and_highest() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        pr.set_voice_allocation(VoiceAllocation::StealLowest);
+        note_on(&mut pr, 64, 127);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 67, 127);
+        assert_eq!(notes(&pr), [64, 67]);
+
+        pr.set_voice_allocation(VoiceAllocation::StealHighest);
+        note_on(&mut pr, 62, 127);
+        assert_eq!(notes(&pr), [64, 62]);
+    }
+
+    #[test]
+    fn stolen_voice_gets_a_rising_gate() {
+        let mut pr = PolyMidiReceiver::<1>::new(0);
+        note_on(&mut pr, 60, 127);
+        pr.rising_gate(0);
+        note_on(&mut pr, 62, 127);
+        assert!(pr.rising_gate(0));
+
+        // the stolen note's note-off does nothing
+        note_off(&mut pr, 60);
+        assert!(pr.gate(0));
+    }
+
+    #[test]
+    fn sustain_holds_every_voice() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        parse_all(&mut pr, &[0xB0, CC_SUSTAIN_SWITCH, 127]);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 64, 127);
+        parse_all(&mut pr, &[0x80, 60, 0, 64, 0]);
+        assert!(pr.gate(0) && pr.gate(1));
+
+        parse_all(&mut pr, &[0xB0, CC_SUSTAIN_SWITCH, 0]);
+        assert!(!pr.gate(0) && !pr.gate(1));
+        assert!(pr.falling_gate(0) && pr.falling_gate(1));
+    }
+
+    #[test]
+    fn all_notes_off_releases_every_voice() {
+        let mut pr = PolyMidiReceiver::<2>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_on(&mut pr, 64, 127);
+        parse_all(&mut pr, &[0xB0, CC_ALL_NOTES_OFF, 0]);
+        assert!(!pr.gate(0) && !pr.gate(1));
+        assert!(pr.falling_gate(0) && pr.falling_gate(1));
+    }
+
+    #[test]
+    fn out_of_range_voices_are_silent() {
+        let mut pr = PolyMidiReceiver::<1>::new(0);
+        note_on(&mut pr, 60, 127);
+        assert!(!pr.gate(1));
+        assert!(!pr.rising_gate(1));
+        assert_eq!(pr.note_num(1), 0);
+    }
+
+    #[test]
+    fn zero_voices_does_not_panic() {
+        let mut pr = PolyMidiReceiver::<0>::new(0);
+        note_on(&mut pr, 60, 127);
+        note_off(&mut pr, 60);
+        assert_eq!(pr.voices(), 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! # Portamento
+//!
+//! Portamento connects a `MonoMidiReceiver` to a `GlideProcessor`, so that MIDI notes slide into each other in the
+//! same way as on classic monophonic synthesizers.
+//!
+//! The MIDI portamento switch (CC65) turns portamento on and off, and the MIDI portamento time (CC5) sets the glide
+//! time. The portamento time is mapped to seconds with a curve that gives fine control over short glide times.
+//!
+//! Two portamento modes are available:
+//!
+//! - `Always` mode glides between every new note
+//!
+//! - `Fingered` mode only glides between overlapping (legato) notes, detached notes jump straight to the new pitch.
+//!   This lets the player choose when to glide by how they play, many classic monosynths work this way.
+//!
+//! The output is the glided note number converted to volts with 1volt/octave scaling, where MIDI note zero is zero
+//! volts.
+
+use crate::{
+    glide_processor::{GlideMode, GlideProcessor},
+    mono_midi_receiver::MonoMidiReceiver,
+};
+
+/// A portamento processor driven by a MIDI receiver is represented here.
+pub struct Portamento {
+    glide: GlideProcessor,
+
+    mode: PortamentoMode,
+
+    // the longest glide time in seconds, reached when the MIDI portamento time is at full scale
+    max_time: f32,
+
+    // the note number seen the last time we processed, used to notice note changes, `None` until a note is played
+    last_note_num: Option<u8>,
+}
+
+impl Portamento {
+    /// `Portamento::new(sr)` is a new portamento processor with sample rate `sr`
+    pub fn new(sample_rate_hz: f32) -> Self {
+        Self {
+            glide: GlideProcessor::new(sample_rate_hz),
+            mode: PortamentoMode::Always,
+            max_time: DEFAULT_MAX_TIME_SEC,
+            last_note_num: None,
+        }
+    }
+
+    /// `p.set_mode(m)` sets the portamento mode to `m`
+    pub fn set_mode(&mut self, mode: PortamentoMode) {
+        self.mode = mode;
+    }
+
+    /// `p.set_glide_mode(m)` sets the glide mode of the internal glide processor to `m`
+    pub fn set_glide_mode(&mut self, mode: GlideMode) {
+        self.glide.set_mode(mode);
+    }
+
+    /// `p.set_max_time(t)` sets the longest glide time to `t` seconds
+    ///
+    /// The longest glide time is reached when the MIDI portamento time is at full scale.
+    pub fn set_max_time(&mut self, t: f32) {
+        self.max_time = t.max(0.0_f32);
+    }
+
+    /// `p.process(mr)` is the current note of the MIDI receiver `mr` with portamento applied, in volts
+    ///
+    /// Must be called periodically at the sample rate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::{mono_midi_receiver::MonoMidiReceiver, portamento::{Portamento, PortamentoMode}};
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// let mut porta = Portamento::new(1_000.0);
+    /// porta.set_mode(PortamentoMode::Fingered);
+    ///
+    /// // set a long portamento time
+    /// mr.parse(0xB0);
+    /// mr.parse(0x05);
+    /// mr.parse(127);
+    ///
+    /// // a detached note jumps right to its pitch
+    /// mr.parse(0x90);
+    /// mr.parse(24);
+    /// mr.parse(127);
+    /// assert_eq!(porta.process(&mr), 2.0);
+    ///
+    /// // an overlapping note glides
+    /// mr.parse(36);
+    /// mr.parse(127);
+    /// assert!(porta.process(&mr) < 3.0);
+    /// ```
+    pub fn process(&mut self, midi: &MonoMidiReceiver) -> f32 {
+        self.glide
+            .set_time(self.portamento_time_to_sec(midi.portamento_time()));
+
+        let note_num = midi.note_num();
+        let volts = note_num as f32 / NUM_NOTES_PER_OCTAVE;
+
+        if self.last_note_num.is_none() && !midi.gate() {
+            // no note has been played yet, so there is nothing to glide from
+            self.glide.reset_to(volts);
+        } else if self.last_note_num != Some(note_num) {
+            let should_glide = self.last_note_num.is_some()
+                && midi.portamento_enabled()
+                && match self.mode {
+                    PortamentoMode::Always => true,
+                    PortamentoMode::Fingered => midi.legato(),
+                };
+
+            if !should_glide {
+                self.glide.reset_to(volts);
+            }
+
+            self.last_note_num = Some(note_num);
+        }
+
+        self.glide.process(volts)
+    }
+
+    /// `p.portamento_time_to_sec(t)` is the MIDI portamento time `t` in `[0.0, 1.0]` converted to seconds
+    ///
+    /// A cubic curve is used so that most of the control range is spent on shorter, more musically useful times.
+    fn portamento_time_to_sec(&self, portamento_time: f32) -> f32 {
+        let t = portamento_time.clamp(0.0_f32, 1.0_f32);
+        t * t * t * self.max_time
+    }
+}
+
+/// Portamento modes are represented here
+///
+/// - `Always` means that every new note glides from the previous note
+///
+/// - `Fingered` means that only overlapping notes glide, notes played after all others are released jump directly to
+///   the new pitch. This is sometimes called "legato" or "auto" portamento.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortamentoMode {
+    Always,
+    Fingered,
+}
+
+/// The default longest glide time in seconds
+pub const DEFAULT_MAX_TIME_SEC: f32 = 5.0_f32;
+
+const NUM_NOTES_PER_OCTAVE: f32 = 12.0_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::*;
+
+    fn receiver_with_portamento_time(val: u8) -> MonoMidiReceiver {
+        let mut mr = MonoMidiReceiver::new(0);
+        mr.parse(0xB0);
+        mr.parse(0x05);
+        mr.parse(val);
+        mr
+    }
+
+    fn note_on(mr: &mut MonoMidiReceiver, note: u8) {
+        mr.parse(0x90);
+        mr.parse(note);
+        mr.parse(127);
+    }
+
+    fn note_off(mr: &mut MonoMidiReceiver, note: u8) {
+        mr.parse(0x80);
+        mr.parse(note);
+        mr.parse(0);
+    }
+
+    #[test]
+    fn first_note_never_glides() {
+        let mut mr = receiver_with_portamento_time(127);
+        let mut porta = Portamento::new(1_000.0);
+
+        note_on(&mut mr, 36);
+        assert_eq!(porta.process(&mr), 3.0);
+    }
+
+    #[test]
+    fn first_note_after_idle_processing_never_glides() {
+        let mut mr = receiver_with_portamento_time(127);
+        let mut porta = Portamento::new(1_000.0);
+
+        for _ in 0..100 {
+            porta.process(&mr);
+        }
+
+        note_on(&mut mr, 60);
+        assert_eq!(porta.process(&mr), 5.0);
+    }
+
+    #[test]
+    fn always_mode_glides_between_detached_notes() {
+        let mut mr = receiver_with_portamento_time(127);
+        let mut porta = Portamento::new(1_000.0);
+
+        note_on(&mut mr, 12);
+        porta.process(&mr);
+        note_off(&mut mr, 12);
+        note_on(&mut mr, 24);
+
+        let v = porta.process(&mr);
+        assert!(1.0 < v && v < 2.0);
+    }
+
+    #[test]
+    fn fingered_mode_only_glides_legato_notes() {
+        let mut mr = receiver_with_portamento_time(127);
+        let mut porta = Portamento::new(1_000.0);
+        porta.set_mode(PortamentoMode::Fingered);
+
+        note_on(&mut mr, 12);
+        porta.process(&mr);
+
+        // detached note jumps
+        note_off(&mut mr, 12);
+        note_on(&mut mr, 24);
+        assert_eq!(porta.process(&mr), 2.0);
+
+        // overlapping note glides
+        note_on(&mut mr, 36);
+        let v = porta.process(&mr);
+        assert!(2.0 < v && v < 3.0);
+    }
+
+    #[test]
+    fn portamento_switch_off_disables_glide() {
+        let mut mr = receiver_with_portamento_time(127);
+        let mut porta = Portamento::new(1_000.0);
+
+        // portamento switch off
+        mr.parse(0xB0);
+        mr.parse(0x41);
+        mr.parse(0);
+
+        note_on(&mut mr, 12);
+        porta.process(&mr);
+        note_on(&mut mr, 24);
+        assert_eq!(porta.process(&mr), 2.0);
+    }
+
+    #[test]
+    fn zero_portamento_time_is_instant() {
+        let mut mr = receiver_with_portamento_time(0);
+        let mut porta = Portamento::new(1_000.0);
+
+        note_on(&mut mr, 12);
+        porta.process(&mr);
+        note_on(&mut mr, 24);
+        porta.process(&mr);
+        assert!(is_almost(porta.process(&mr), 2.0, 0.005));
+    }
+
+    #[test]
+    fn time_curve_favors_short_times() {
+        let porta = Portamento::new(1_000.0);
+        assert_eq!(porta.portamento_time_to_sec(0.0), 0.0);
+        assert_eq!(porta.portamento_time_to_sec(1.0), DEFAULT_MAX_TIME_SEC);
+        assert!(porta.portamento_time_to_sec(0.5) < DEFAULT_MAX_TIME_SEC / 4.0);
+    }
+}
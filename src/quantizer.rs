@@ -6,19 +6,83 @@
 //! This quantizer operates similarly to common hardware quantizers, using 1volt/octave scaling. This means that each
 //! octave spans 1 volt, and so each semitone spans 1/12 of a volt, or about 83.3mV
 //!
-//! Specific notes may be allowed or forbidden, allowing the user to program user defined scales.
+//! Other scalings such as the 1.2volt/octave used by Buchla may be set instead. The input range defaults to
+//! `[0.0, 10.0]` volts, but may be set to any range including negative voltages such as `[-5.0, 5.0]`, note numbers
+//! below zero volts are negative.
+//!
+//! Specific notes may be allowed or forbidden, allowing the user to program user defined scales. A catalog of common
+//! named scales is also provided, any of which may be loaded with a chosen root note.
+//!
+//! By default the octave is divided into 12 equal semitones, but any `Tuning` may be used instead. This allows for
+//! microtonal quantizing with other equal divisions of the octave, or just intonation pitch sets. In other tunings
+//! the "notes" are the steps of the tuning, and `allow_steps` and `forbid_steps` are used to choose which are allowed.
+//!
+//! Inputs are normally truncated to the note just below them, falling back to the nearest allowed note if that note is
+//! forbidden. They may also be rounded to the nearest, down, or up to match the feel of different hardware quantizers. A configurable amount of hysteresis keeps noisy inputs from chattering between
+//! neighboring notes.
+//!
+//! Conversions may be transposed after quantizing, either by scale degrees so that they stay in the scale, by steps of
+//! the tuning (semitones in 12-TET), or by whole octaves.
+//!
+//! For generative patches each step may be given a weight, and in weighted selection mode the quantizer picks randomly
+//! between the closest allowed step and its allowed neighbors, favoring steps with higher weights. The random numbers
+//! are seedable, so results can be repeated. The default deterministic mode ignores the weights.
+//!
+//! The allowed notes, scale, hysteresis, and rounding may be saved as small presets, for storing in EEPROM or flash.
+//! A `PresetBank` holds a fixed number of presets which may be stored and recalled.
+//!
+//! Like hardware quantizers, each conversion says whether the note has changed, which is handy for firing a trigger
+//! output. The quantizer may also be clocked, so that it only follows the input when it receives a trigger and holds
+//! the last conversion otherwise, like a sample and hold.
 
-use heapless::Vec;
+use crate::{
+    tuning::{Tuning, MAX_STEPS_PER_PERIOD, PITCH_TOLERANCE},
+    utils::*,
+};
 
 /// A quantizer which converts smooth inputs into stairsteps is represented here.
 pub struct Quantizer {
     // save the last conversion for hysteresis purposes
     cached_conversion: Conversion,
 
-    // allowed notes are represented as an integer bitfield
-    // the 12 lowest bits represent C, C#, D, ... B
-    // a set-bit means the note is allowed, cleared-bit means the note is forbidden
-    allowed: u16,
+    // the step the input was last rounded to, in weighted selection mode the conversion may be a different step
+    target_step: i32,
+
+    // the tuning sets the pitch of each step
+    tuning: Tuning,
+
+    // allowed steps are represented as an integer bitfield
+    // the lowest bits represent the steps in each period of the tuning, in 12-TET the 12 lowest bits represent C, C#,
+    // D, ... B
+    // a set-bit means the step is allowed, cleared-bit means the step is forbidden
+    allowed: u64,
+
+    // the most recently set named scale and root, used to pick the right name when a mask matches several scales
+    scale_hint: Option<(Scale, Note)>,
+
+    rounding: Rounding,
+
+    // hysteresis as a fraction of the width of a step
+    hysteresis: f32,
+
+    // the input range in volts
+    v_min: f32,
+    v_max: f32,
+
+    volts_per_octave: f32,
+
+    sample_mode: SampleMode,
+
+    // in clocked mode the next conversion only happens after a trigger
+    trigger_pending: bool,
+
+    selection_mode: SelectionMode,
+
+    // the weight of each step in each period of the tuning, for weighted selection mode
+    weights: [u8; MAX_STEPS_PER_PERIOD],
+
+    // xorshift random number generator state, never zero
+    rng_state: u32,
 }
 
 /// A quantizer conversion is represented here.
@@ -27,18 +91,22 @@ pub struct Quantizer {
 /// The stairstep is the input value converted to a stairstep with as many steps as there are semitones, and the
 /// fractional part is the difference between the actual input value and the quantized stairstep.
 ///
-/// The stairstep will always be positive, the fraction may be positive or negative.
+/// The stairstep and the fraction may be positive or negative.
 /// The stairstep plus the fraction will get us back to the original input value.
 ///
-/// The integer note number is also included.
+/// The integer note number is also included, along with a flag which is only set when the note number has changed.
 #[derive(Clone, Copy)]
 pub struct Conversion {
-    /// The integer note number of the conversion
-    pub note_num: u8,
+    /// The integer note number of the conversion, this is the number of steps of the tuning above zero volts, negative
+    /// note numbers are below zero volts
+    pub note_num: i16,
     /// The conversion as a stairstep pattern, in the same range as the input except quantized to discrete steps
     pub stairstep: f32,
     /// The fractional remainder of the stairstep, `stairstep + fraction` results in the original input value
     pub fraction: f32,
+    /// True iff the note number is different from the previous conversion, false for all following conversions until
+    /// the note changes again
+    pub note_changed: bool,
 }
 
 #[allow(clippy::new_without_default)]
@@ -49,17 +117,31 @@ impl Conversion {
             note_num: 0,
             stairstep: f32::MIN, // initialized so that hysteresis doesn't influence the first conversion
             fraction: 0.0_f32,
+            note_changed: false,
         }
     }
 }
 
 #[allow(clippy::new_without_default)]
 impl Quantizer {
-    /// `Quantizer::new()` is a new quantizer with all notes allowed.
+    /// `Quantizer::new()` is a new 12-TET quantizer with all notes allowed.
     pub fn new() -> Self {
         Self {
             cached_conversion: Conversion::new(),
+            target_step: 0,
+            tuning: Tuning::equal_temperament(12),
             allowed: 0b0000_1111_1111_1111, // all 12 notes allowed
+            scale_hint: None,
+            rounding: Rounding::Truncate,
+            hysteresis: DEFAULT_HYSTERESIS,
+            v_min: DEFAULT_V_MIN,
+            v_max: DEFAULT_V_MAX,
+            volts_per_octave: 1.0_f32,
+            sample_mode: SampleMode::Continuous,
+            trigger_pending: false,
+            selection_mode: SelectionMode::Deterministic,
+            weights: [MAX_WEIGHT; MAX_STEPS_PER_PERIOD],
+            rng_state: DEFAULT_SEED,
         }
     }
 
@@ -67,9 +149,16 @@ impl Quantizer {
     ///
     /// The input is split into a stairstep component and fractional component.
     ///
+    /// In clocked mode the input is only converted if there has been a trigger since the last conversion, otherwise
+    /// the previous conversion is held and returned again. The very first conversion always happens, so that there is
+    /// something to hold.
+    ///
+    /// In weighted selection mode a new step is picked whenever the input moves to a different step, and on every
+    /// trigger in clocked mode.
+    ///
     /// # Arguments
     ///
-    /// * `v_in` - the value to quantize, in volts, clamped to `[0.0, V_MAX]`
+    /// * `v_in` - the value to quantize, in volts, clamped to the input range
     ///
     /// # Returns
     ///
@@ -89,311 +178,1822 @@ impl Quantizer {
     /// ```
     ///
     pub fn convert(&mut self, v_in: f32) -> Conversion {
-        // return early if vin is within the window of the last coversion plus a little hysteresis
-        if self.is_allowed(self.cached_conversion.note_num.into()) {
-            let low_bound = self.cached_conversion.stairstep - HYSTERESIS;
-            let high_bound = self.cached_conversion.stairstep + SEMITONE_WIDTH + HYSTERESIS;
+        // the note number never changes more than once for the same note, so the flag is always cleared first
+        self.cached_conversion.note_changed = false;
 
-            if low_bound < v_in && v_in < high_bound {
-                self.cached_conversion.fraction = v_in - self.cached_conversion.stairstep;
+        let has_converted = self.cached_conversion.stairstep != f32::MIN;
+        let mut should_pick_again = false;
+        if self.sample_mode == SampleMode::Clocked && has_converted {
+            if !self.trigger_pending {
                 return self.cached_conversion;
             }
+            self.trigger_pending = false;
+            should_pick_again = self.selection_mode == SelectionMode::Weighted;
         }
 
-        let v_in = v_in.max(0.0_f32).min(V_MAX);
+        let v_in = v_in.clamp(self.v_min, self.v_max);
+        let pitch = self.volts_to_octaves(v_in);
+
+        // return early if vin is within the window of the last coversion plus a little hysteresis
+        let cached_step = self.target_step;
+        if has_converted
+            && !should_pick_again
+            && self.is_step_allowed(self.step_in_period(cached_step))
+            && self.find_step(self.pull_toward_step(pitch, cached_step)) == cached_step
+        {
+            self.cached_conversion.fraction = v_in - self.cached_conversion.stairstep;
+            return self.cached_conversion;
+        }
 
-        self.cached_conversion.note_num = self.find_nearest_note(v_in);
-        self.cached_conversion.stairstep = self.cached_conversion.note_num as f32 / 12.0_f32;
+        self.target_step = self.find_step(pitch);
+        let step = match self.selection_mode {
+            SelectionMode::Deterministic => self.target_step,
+            SelectionMode::Weighted => self.pick_weighted_step(self.target_step),
+        };
+        self.cached_conversion.note_changed =
+            !has_converted || self.cached_conversion.note_num != step as i16;
+        self.cached_conversion.note_num = step as i16;
+        self.cached_conversion.stairstep = self.tuning.pitch(step) * self.volts_per_octave;
         self.cached_conversion.fraction = v_in - self.cached_conversion.stairstep;
 
         self.cached_conversion
     }
 
-    /// `q.find_nearest_note(v)` is 1volt/octave voltage `v` converted to the nearest semitone number
-    fn find_nearest_note(&self, v_in: f32) -> u8 {
-        let vin_microvolts = (v_in * ONE_OCTAVE_IN_MICROVOLTS as f32) as u32;
-        let octave_num_of_vin = vin_microvolts / ONE_OCTAVE_IN_MICROVOLTS;
+    /// `q.find_step(p)` is pitch `p` in octaves converted to an allowed step number with the rounding mode
+    ///
+    /// If there is no allowed step in the rounding direction, which can happen near the ends of the range, the nearest
+    /// allowed step is used instead
+    fn find_step(&self, pitch: f32) -> i32 {
+        // the allowed step might be in the same period as the input, but it might also be in the period above or
+        // below. We can't go below the lowest period or above the highest period, so near the edges there is less to
+        // check
+        let num_steps = self.tuning.num_steps() as i32;
+        let first_step = (self.tuning.step_below(pitch).div_euclid(num_steps) - 1) * num_steps;
+        let min_step = self.min_step();
+        let max_step = self.max_step();
 
-        // we want to look in either two or three octaves to find the nearest note
-        // it might be in the same octave as the input, but the nearest note might also be in the octave above or below
-        // we can't go below octave zero or above MAX_OCTAVE, so there might be only two to check if we're near an edge
-        let mut octaves_to_search = Vec::<u32, 3>::new();
-        octaves_to_search.push(octave_num_of_vin).ok();
-        if 1 <= octave_num_of_vin {
-            octaves_to_search.push(octave_num_of_vin - 1).ok();
-        }
-        if octave_num_of_vin < MAX_OCTAVE {
-            octaves_to_search.push(octave_num_of_vin + 1).ok();
+        if self.rounding == Rounding::Truncate {
+            let step_below = self.tuning.step_below(pitch).clamp(min_step, max_step);
+            if self.is_step_allowed(self.step_in_period(step_below)) {
+                return step_below;
+            }
         }
 
-        let mut nearest_note_so_far_microvolts = 0;
-        let mut smallest_delta_so_far = u32::MAX;
+        let mut nearest_step_so_far = 0;
+        let mut smallest_delta_so_far = f32::MAX;
 
-        for octave in octaves_to_search {
-            for n in 0..12 {
-                let this_note_is_enabled = (self.allowed >> n) & 1 == 1;
+        let mut rounded_step_so_far = None;
+        let mut smallest_rounded_delta_so_far = f32::MAX;
 
-                if this_note_is_enabled {
-                    let candidate_note_microvolts =
-                        n * HALF_STEP_IN_MICROVOLTS + octave * ONE_OCTAVE_IN_MICROVOLTS;
-
-                    let delta = delta(vin_microvolts, candidate_note_microvolts);
-
-                    // early return if we get very close to an enabled note, this must be the one
-                    if delta < HALF_STEP_IN_MICROVOLTS {
-                        return (candidate_note_microvolts / HALF_STEP_IN_MICROVOLTS) as u8;
-                    }
+        for step in first_step.max(min_step)..(first_step + 3 * num_steps).min(max_step + 1) {
+            if !self.is_step_allowed(self.step_in_period(step)) {
+                continue;
+            }
 
-                    // early return if delta starts getting bigger, this means that we passed the right note
-                    if smallest_delta_so_far < delta {
-                        return (nearest_note_so_far_microvolts / HALF_STEP_IN_MICROVOLTS) as u8;
-                    }
+            let delta = pitch - self.tuning.pitch(step);
+            if fabs(delta) < smallest_delta_so_far {
+                smallest_delta_so_far = fabs(delta);
+                nearest_step_so_far = step;
+            }
 
-                    if delta < smallest_delta_so_far {
-                        smallest_delta_so_far = delta;
-                        nearest_note_so_far_microvolts = candidate_note_microvolts;
-                    }
-                }
+            let is_in_rounding_direction = match self.rounding {
+                // a truncated step which is forbidden falls back to the nearest allowed step
+                Rounding::Nearest | Rounding::Truncate => true,
+                Rounding::Floor => -PITCH_TOLERANCE < delta,
+                Rounding::Ceil => delta < PITCH_TOLERANCE,
+            };
+            if is_in_rounding_direction && fabs(delta) < smallest_rounded_delta_so_far {
+                smallest_rounded_delta_so_far = fabs(delta);
+                rounded_step_so_far = Some(step);
             }
         }
 
-        (nearest_note_so_far_microvolts / HALF_STEP_IN_MICROVOLTS) as u8
+        rounded_step_so_far.unwrap_or(nearest_step_so_far)
     }
 
-    /// `q.allow(ns)` allows notes `ns`, meaning they will be included in conversions
+    /// `q.min_step()` is the lowest step number the quantizer can convert to, the first step of the lowest period
     ///
-    /// Any notes in `ns` that are already allowed are left unchanged
-    pub fn allow(&mut self, notes: &[Note]) {
-        notes.iter().for_each(|n| {
-            self.allowed |= 1 << n.0;
-        })
+    /// Step numbers are limited to the range of an `i16`, so that they fit in a note number.
+    fn min_step(&self) -> i32 {
+        let num_steps = self.tuning.num_steps() as i32;
+        (floor(self.volts_to_octaves(self.v_min) / self.tuning.period()) * num_steps)
+            .max(MIN_NOTE_NUM)
     }
 
-    /// `q.forbid(ns)` forbids notes `ns`, they will not be included in conversions even if they are the nearest note
-    ///
-    /// Any notes in `ns` that are already forbidden are left unchanged
+    /// `q.pick_weighted_step(s)` is a random choice of step `s` and the nearest allowed steps above and below it
     ///
-    /// At least one note must always be left allowed. If `ns` would forbid every note, the last note in `ns` will not
-    /// be forbidden and instead will be left allowed.
-    pub fn forbid(&mut self, notes: &[Note]) {
-        notes.iter().for_each(|n| self.allowed &= !(1 << n.0));
-        if self.allowed == 0 {
-            self.allow(&notes[notes.len() - 1..])
+    /// The chance of each step being picked is proportional to its weight. If all the weights are zero `s` is picked.
+    fn pick_weighted_step(&mut self, step: i32) -> i32 {
+        let candidates = [
+            Some(step),
+            self.next_allowed_step(step, -1),
+            self.next_allowed_step(step, 1),
+        ];
+        let weights =
+            candidates.map(|s| s.map_or(0, |s| self.step_weight(self.step_in_period(s)) as u32));
+
+        let total_weight: u32 = weights.iter().sum();
+        if total_weight == 0 {
+            return step;
         }
-    }
 
-    /// `q.is_allowed(n)` is true iff note `n` is allowed
-    pub fn is_allowed(&self, note: Note) -> bool {
-        self.allowed >> note.0 & 1 == 1
+        let mut choice = self.next_random() % total_weight;
+        for (candidate, weight) in candidates.iter().zip(weights) {
+            match candidate {
+                Some(candidate) if choice < weight => return *candidate,
+                _ => choice -= weight,
+            }
+        }
+
+        step
     }
-}
 
-fn delta(v1: u32, v2: u32) -> u32 {
-    if v1 < v2 {
-        v2 - v1
-    } else {
-        v1 - v2
+    /// `q.next_random()` is the next number from the xorshift random number generator
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        x
     }
-}
 
-/// Note names are represented here, the quantizer can allow and forbid various notes from being converted
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub struct Note(u8);
+    /// `q.next_allowed_step(s, d)` is the first allowed step after step `s` in direction `d`, `None` if out of range
+    fn next_allowed_step(&self, step: i32, direction: i32) -> Option<i32> {
+        let min_step = self.min_step();
+        let max_step = self.max_step();
 
-impl Note {
-    pub const C: Self = Self::new(0);
-    pub const CSHARP: Self = Self::new(1);
-    pub const D: Self = Self::new(2);
-    pub const DSHARP: Self = Self::new(3);
-    pub const E: Self = Self::new(4);
-    pub const F: Self = Self::new(5);
-    pub const FSHARP: Self = Self::new(6);
-    pub const G: Self = Self::new(7);
-    pub const GSHARP: Self = Self::new(8);
-    pub const A: Self = Self::new(9);
-    pub const ASHARP: Self = Self::new(10);
-    pub const B: Self = Self::new(11);
+        let mut candidate = step + direction;
+        while min_step <= candidate && candidate <= max_step {
+            if self.is_step_allowed(self.step_in_period(candidate)) {
+                return Some(candidate);
+            }
+            candidate += direction;
+        }
 
-    /// `Note::new(n)` is a new note from `n` clamped to `[0..11]`
-    pub const fn new(n: u8) -> Self {
-        Self(if n <= 11 { n } else { 11 })
+        None
     }
-}
 
-impl From<u8> for Note {
-    fn from(n: u8) -> Self {
-        Self::new(n)
+    /// `q.set_selection_mode(m)` sets the selection mode to `m`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, SelectionMode, Note, Scale};
+    /// let mut q = Quantizer::new();
+    /// q.set_scale(Scale::MajorPentatonic, Note::C);
+    /// q.set_selection_mode(SelectionMode::Weighted);
+    ///
+    /// // E is never picked, D and G are equally likely
+    /// q.set_weight(Note::E, 0);
+    ///
+    /// let note_num = q.convert(4.0 / 12.0).note_num;
+    /// assert!(note_num == 2 || note_num == 7);
+    /// ```
+    pub fn set_selection_mode(&mut self, mode: SelectionMode) {
+        self.selection_mode = mode;
     }
-}
 
-impl From<Note> for u8 {
-    fn from(n: Note) -> Self {
-        n.0
+    /// `q.selection_mode()` is the current selection mode
+    pub fn selection_mode(&self) -> SelectionMode {
+        self.selection_mode
     }
-}
 
-pub const NUM_NOTES_PER_OCTAVE: f32 = 12.0_f32;
+    /// `q.set_weight(n, w)` sets the weight of note `n` to `w`, higher weights are more likely to be picked
+    ///
+    /// Weights only matter in weighted selection mode. A weight of zero means the note is never picked unless all of
+    /// the nearby notes also have zero weight. Forbidden notes are never picked whatever their weight.
+    pub fn set_weight(&mut self, note: Note, weight: u8) {
+        self.set_step_weight(note.0, weight)
+    }
 
-/// The width of each bucket for the semitones.
-pub const SEMITONE_WIDTH: f32 = 1.0_f32 / NUM_NOTES_PER_OCTAVE;
-pub const HALF_SEMITONE_WIDTH: f32 = SEMITONE_WIDTH / 2.0_f32;
+    /// `q.weight(n)` is the weight of note `n`
+    pub fn weight(&self, note: Note) -> u8 {
+        self.step_weight(note.0)
+    }
 
-/// Hysteresis provides some noise immunity and prevents oscillations near transition regions.
-const HYSTERESIS: f32 = SEMITONE_WIDTH * 0.1_f32;
+    /// `q.set_step_weight(s, w)` sets the weight of step `s` of the tuning to `w`, steps past the end of the period are
+    /// ignored
+    pub fn set_step_weight(&mut self, step: u8, weight: u8) {
+        if (step as usize) < self.tuning.num_steps() {
+            self.weights[step as usize] = weight;
+        }
+    }
 
-const ONE_OCTAVE_IN_MICROVOLTS: u32 = 1_000_000;
+    /// `q.step_weight(s)` is the weight of step `s` of the tuning, zero for steps past the end of the period
+    pub fn step_weight(&self, step: u8) -> u8 {
+        if (step as usize) < self.tuning.num_steps() {
+            self.weights[step as usize]
+        } else {
+            0
+        }
+    }
 
-const HALF_STEP_IN_MICROVOLTS: u32 = ONE_OCTAVE_IN_MICROVOLTS / 12;
+    /// `q.seed(s)` seeds the random number generator used in weighted selection mode with `s`
+    ///
+    /// The same seed and inputs always give the same conversions. Zero is not a valid seed for the generator, so it is
+    /// replaced with the default seed.
+    pub fn seed(&mut self, seed: u32) {
+        self.rng_state = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
 
-const MAX_OCTAVE: u32 = 10;
+    /// `q.max_step()` is the highest step number the quantizer can convert to, the last step of the highest period
+    ///
+    /// Step numbers are limited to the range of an `i16`, so that they fit in a note number.
+    fn max_step(&self) -> i32 {
+        let num_steps = self.tuning.num_steps() as i32;
+        ((floor(self.volts_to_octaves(self.v_max) / self.tuning.period()) + 1) * num_steps - 1)
+            .min(MAX_NOTE_NUM)
+    }
 
-const V_MAX: f32 = MAX_OCTAVE as f32;
+    /// `q.volts_to_octaves(v)` is voltage `v` converted to a pitch in octaves with the volts per octave scaling
+    ///
+    /// The pitch is limited to the pitches of the lowest and highest steps whose step numbers fit in an `i16`. How
+    /// many octaves that is depends on the tuning and the scaling.
+    fn volts_to_octaves(&self, v: f32) -> f32 {
+        (v / self.volts_per_octave).clamp(
+            self.tuning.pitch(MIN_NOTE_NUM),
+            self.tuning.pitch(MAX_NOTE_NUM),
+        )
+    }
 
-#[cfg(test)]
-#[allow(non_snake_case)]
-mod tests {
-    use super::*;
+    /// `q.set_range(lo, hi)` sets the input range to `[lo, hi]` volts, inputs outside the range are clamped
+    ///
+    /// If `lo` is above `hi` they are swapped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::Quantizer;
+    /// let mut q = Quantizer::new();
+    /// q.set_range(-5.0, 5.0);
+    /// assert_eq!(q.convert(-1.0).note_num, -12);
+    /// ```
+    pub fn set_range(&mut self, v_min: f32, v_max: f32) {
+        self.v_min = v_min.min(v_max);
+        self.v_max = v_max.max(v_min);
+        self.cached_conversion = Conversion::new();
+    }
 
-    #[test]
-    fn vin_0_is_note_num_zero_with_all_allowed() {
-        let mut q = Quantizer::new();
-        assert_eq!(q.convert(0.0).note_num, 0);
+    /// `q.range()` is the input range in volts, as `(lo, hi)`
+    pub fn range(&self) -> (f32, f32) {
+        (self.v_min, self.v_max)
     }
 
-    #[test]
-    fn vin_point_08333_is_note_num_1_with_all_allowed() {
-        let mut q = Quantizer::new();
-        assert_eq!(q.convert(1. / 12.).note_num, 1);
+    /// `q.set_volts_per_octave(v)` sets the scaling of inputs and stairsteps to `v` volts per octave
+    ///
+    /// The scaling is clamped to be at least `MIN_VOLTS_PER_OCTAVE`. The input range is in volts, so it is not changed
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::Quantizer;
+    /// let mut q = Quantizer::new();
+    /// // Buchla scaling
+    /// q.set_volts_per_octave(1.2);
+    /// let conversion = q.convert(1.2);
+    /// assert_eq!(conversion.note_num, 12);
+    /// assert!((conversion.stairstep - 1.2).abs() < 0.000_1);
+    /// ```
+    pub fn set_volts_per_octave(&mut self, volts_per_octave: f32) {
+        self.volts_per_octave = volts_per_octave.max(MIN_VOLTS_PER_OCTAVE);
+        self.cached_conversion = Conversion::new();
     }
 
-    #[test]
-    fn vin_1_is_note_num_12_with_all_allowed() {
-        let mut q = Quantizer::new();
-        assert_eq!(q.convert(1.).note_num, 12);
+    /// `q.volts_per_octave()` is the current scaling in volts per octave
+    pub fn volts_per_octave(&self) -> f32 {
+        self.volts_per_octave
     }
 
-    #[test]
-    fn vin_2_point_08333_is_note_num_25_with_all_allowed() {
-        let mut q = Quantizer::new();
-        assert_eq!(q.convert(2. + 1. / 12.).note_num, 25);
+    /// `q.transpose_degrees(c, n)` is conversion `c` moved up by `n` allowed steps, or down if `n` is negative
+    ///
+    /// This is diatonic transposition, the result stays in the scale. If `c` is not on an allowed step the first
+    /// degree moves to the next allowed step in that direction. Transposing stops at the lowest or highest allowed step
+    /// in range.
+    ///
+    /// The fraction of `c` is kept, so `stairstep + fraction` is the input value transposed by the same amount.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, Note, Scale};
+    /// let mut q = Quantizer::new();
+    /// q.set_scale(Scale::Major, Note::C);
+    ///
+    /// // up a third from C is E
+    /// let c = q.convert(0.0);
+    /// assert_eq!(q.transpose_degrees(c, 2).note_num, 4);
+    ///
+    /// // down a third from E is C
+    /// let e = q.convert(4.0 / 12.0);
+    /// assert_eq!(q.transpose_degrees(e, -2).note_num, 0);
+    /// ```
+    pub fn transpose_degrees(&self, conversion: Conversion, degrees: i32) -> Conversion {
+        let mut step = conversion.note_num as i32;
+        for _ in 0..degrees.abs() {
+            match self.next_allowed_step(step, degrees.signum()) {
+                Some(next) => step = next,
+                None => break,
+            }
+        }
+
+        self.conversion_at_step(conversion, step)
     }
 
-    #[test]
-    fn when_C_is_forbidden_vin_0_is_1() {
-        let mut q = Quantizer::new();
-        q.forbid(&[Note::C]);
-        assert_eq!(q.convert(0.0).note_num, 1);
+    /// `q.transpose_steps(c, n)` is conversion `c` moved up by `n` steps of the tuning, or down if `n` is negative
+    ///
+    /// This is chromatic transposition, in 12-TET the steps are semitones. The result may land on a forbidden step.
+    /// The result is clamped to the range of the quantizer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::Quantizer;
+    /// let mut q = Quantizer::new();
+    /// let c = q.convert(0.0);
+    /// // up a perfect fifth
+    /// assert_eq!(q.transpose_steps(c, 7).note_num, 7);
+    /// ```
+    pub fn transpose_steps(&self, conversion: Conversion, steps: i32) -> Conversion {
+        let step = (conversion.note_num as i32 + steps).clamp(self.min_step(), self.max_step());
+        self.conversion_at_step(conversion, step)
     }
 
-    #[test]
-    fn when_only_B_is_allowed_vin_0_is_11() {
-        let mut q = Quantizer::new();
-        q.forbid(&[
-            Note::C,
-            Note::CSHARP,
-            Note::D,
-            Note::DSHARP,
-            Note::E,
-            Note::F,
-            Note::FSHARP,
-            Note::G,
-            Note::GSHARP,
-            Note::A,
-            Note::ASHARP,
-            // Note::B,
-        ]);
-        assert_eq!(q.convert(0.0).note_num, 11);
+    /// `q.shift_octaves(c, n)` is conversion `c` moved up by `n` octaves, or down if `n` is negative
+    ///
+    /// For tunings which don't repeat at the octave this shifts by whole periods of the tuning instead. The result is
+    /// clamped to the range of the quantizer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::Quantizer;
+    /// let mut q = Quantizer::new();
+    /// let c = q.convert(2.0);
+    /// assert_eq!(q.shift_octaves(c, -1).note_num, 12);
+    /// ```
+    pub fn shift_octaves(&self, conversion: Conversion, octaves: i32) -> Conversion {
+        self.transpose_steps(conversion, octaves * self.tuning.num_steps() as i32)
     }
 
-    #[test]
-    fn when_only_Dsharp_is_allowed_vin_8_12ths_is_3() {
-        let mut q = Quantizer::new();
-        q.forbid(&[
-            Note::C,
-            Note::CSHARP,
-            Note::D,
-            // Note::Dsharp,
-            Note::E,
-            Note::F,
-            Note::FSHARP,
-            Note::G,
-            Note::GSHARP,
-            Note::A,
-            Note::ASHARP,
-            Note::B,
-        ]);
-        // it picks the D# in octave zero
-        assert_eq!(q.convert(8. / 12.).note_num, 3);
+    /// `q.conversion_at_step(c, s)` is conversion `c` moved to step `s`, keeping its fraction and note changed flag
+    fn conversion_at_step(&self, conversion: Conversion, step: i32) -> Conversion {
+        Conversion {
+            note_num: step as i16,
+            stairstep: self.tuning.pitch(step) * self.volts_per_octave,
+            ..conversion
+        }
     }
 
-    #[test]
-    fn when_only_Dsharp_is_allowed_vin_10_12ths_is_15() {
-        let mut q = Quantizer::new();
-        q.forbid(&[
-            Note::C,
-            Note::CSHARP,
-            Note::D,
-            // Note::Dsharp,
-            Note::E,
-            Note::F,
-            Note::FSHARP,
-            Note::G,
-            Note::GSHARP,
-            Note::A,
-            Note::ASHARP,
-            Note::B,
-        ]);
-        // it picks the D# in octave 1
-        assert_eq!(q.convert(10. / 12.).note_num, 15);
+    /// `q.pull_toward_step(p, s)` is pitch `p` moved toward the pitch of step `s` by the hysteresis, without passing it
+    ///
+    /// If the moved pitch still converts to step `s` then `p` is within the hysteresis window of step `s`.
+    fn pull_toward_step(&self, pitch: f32, step: i32) -> f32 {
+        let step_pitch = self.tuning.pitch(step);
+        if step_pitch < pitch {
+            let step_width = self.tuning.pitch(step + 1) - step_pitch;
+            (pitch - self.hysteresis * step_width).max(step_pitch)
+        } else {
+            let step_width = step_pitch - self.tuning.pitch(step - 1);
+            (pitch + self.hysteresis * step_width).min(step_pitch)
+        }
     }
 
-    #[test]
-    fn can_not_forbid_every_note() {
-        let mut q = Quantizer::new();
-        // try to forbid every note
-        q.forbid(&[
-            Note::C,
-            Note::CSHARP,
-            Note::D,
-            Note::DSHARP,
-            Note::E,
-            Note::F,
-            Note::FSHARP,
-            Note::G,
-            Note::GSHARP,
-            Note::A,
-            Note::ASHARP,
-            Note::B,
-        ]);
-        // B is still left, because it is the last one we tried to forbid
-        assert_eq!(q.convert(0.5).note_num, 11);
+    /// `q.set_rounding(r)` sets the rounding mode to `r`, the default is `Rounding::Truncate`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, Rounding};
+    /// let mut q = Quantizer::new();
+    /// assert_eq!(q.convert(0.9 / 12.).note_num, 0);
+    ///
+    /// let mut q = Quantizer::new();
+    /// q.set_rounding(Rounding::Nearest);
+    /// assert_eq!(q.convert(0.9 / 12.).note_num, 1);
+    /// ```
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
     }
 
-    #[test]
-    fn hysteresis_widens_window() {
-        let mut q = Quantizer::new();
+    /// `q.rounding()` is the current rounding mode
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    /// `q.set_hysteresis(h)` sets the hysteresis to `h`, a fraction of the width of a step
+    ///
+    /// Once the input has been converted to a step it has to move past the edge of that step by this much more before
+    /// the conversion will change. Zero turns hysteresis off. The hysteresis is clamped to `[0.0, MAX_HYSTERESIS]`
+    pub fn set_hysteresis(&mut self, hysteresis: f32) {
+        self.hysteresis = hysteresis.clamp(0.0_f32, MAX_HYSTERESIS);
+    }
+
+    /// `q.hysteresis()` is the current hysteresis, as a fraction of the width of a step
+    pub fn hysteresis(&self) -> f32 {
+        self.hysteresis
+    }
+
+    /// `q.set_sample_mode(m)` sets the sample mode to `m`
+    pub fn set_sample_mode(&mut self, mode: SampleMode) {
+        self.sample_mode = mode;
+        self.trigger_pending = false;
+    }
+
+    /// `q.sample_mode()` is the current sample mode
+    pub fn sample_mode(&self) -> SampleMode {
+        self.sample_mode
+    }
+
+    /// `q.trigger()` lets the next conversion follow the input when in clocked mode, it has no effect otherwise
+    ///
+    /// Triggers don't stack up, several triggers before the next conversion count as one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, SampleMode};
+    /// let mut q = Quantizer::new();
+    /// q.set_sample_mode(SampleMode::Clocked);
+    ///
+    /// assert_eq!(q.convert(0.0).note_num, 0);
+    ///
+    /// // without a trigger the last conversion is held
+    /// assert_eq!(q.convert(0.5).note_num, 0);
+    ///
+    /// q.trigger();
+    /// let conversion = q.convert(0.5);
+    /// assert_eq!(conversion.note_num, 6);
+    /// assert!(conversion.note_changed);
+    /// ```
+    pub fn trigger(&mut self) {
+        self.trigger_pending = true;
+    }
+
+    /// `q.step_in_period(s)` is the absolute step number `s` folded into the first period of the tuning
+    fn step_in_period(&self, step: i32) -> u8 {
+        step.rem_euclid(self.tuning.num_steps() as i32) as u8
+    }
+
+    /// `q.set_tuning(t)` sets the tuning of the quantizer to `t`
+    ///
+    /// All of the steps of the new tuning are allowed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::{quantizer::Quantizer, tuning::Tuning};
+    /// let mut q = Quantizer::new();
+    /// q.set_tuning(Tuning::equal_temperament(24));
+    ///
+    /// // quarter tones
+    /// assert_eq!(q.convert(1.5 / 12.).note_num, 3);
+    /// ```
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.allowed = all_steps(tuning.num_steps());
+        self.tuning = tuning;
+        self.cached_conversion = Conversion::new();
+        self.scale_hint = None;
+    }
+
+    /// `q.tuning()` is the current tuning of the quantizer
+    pub fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    /// `q.allow(ns)` allows notes `ns`, meaning they will be included in conversions
+    ///
+    /// Any notes in `ns` that are already allowed are left unchanged
+    ///
+    /// Notes are the same as steps `[0..11]` of the tuning, see `allow_steps`
+    pub fn allow(&mut self, notes: &[Note]) {
+        notes.iter().for_each(|n| self.allow_step(n.0))
+    }
+
+    /// `q.forbid(ns)` forbids notes `ns`, they will not be included in conversions even if they are the nearest note
+    ///
+    /// Any notes in `ns` that are already forbidden are left unchanged
+    ///
+    /// At least one note must always be left allowed. If `ns` would forbid every note, the last note in `ns` which is a
+    /// step of the tuning will not be forbidden and instead will be left allowed.
+    ///
+    /// Notes are the same as steps `[0..11]` of the tuning, see `forbid_steps`
+    pub fn forbid(&mut self, notes: &[Note]) {
+        notes.iter().for_each(|n| self.forbid_step(n.0));
+        self.keep_one_allowed(notes.iter().map(|n| n.0));
+    }
+
+    /// `q.is_allowed(n)` is true iff note `n` is allowed
+    pub fn is_allowed(&self, note: Note) -> bool {
+        self.is_step_allowed(note.0)
+    }
+
+    /// `q.allow_steps(ss)` allows steps `ss` of the tuning, meaning they will be included in conversions
+    ///
+    /// Steps are numbered from zero within each period of the tuning, steps past the end of the period are ignored.
+    pub fn allow_steps(&mut self, steps: &[u8]) {
+        steps.iter().for_each(|s| self.allow_step(*s))
+    }
+
+    /// `q.forbid_steps(ss)` forbids steps `ss` of the tuning, they will not be included in conversions
+    ///
+    /// At least one step must always be left allowed. If `ss` would forbid every step, the last step in `ss` which is
+    /// a step of the tuning will not be forbidden and instead will be left allowed.
+    pub fn forbid_steps(&mut self, steps: &[u8]) {
+        steps.iter().for_each(|s| self.forbid_step(*s));
+        self.keep_one_allowed(steps.iter().copied());
+    }
+
+    /// `q.is_step_allowed(s)` is true iff step `s` of the tuning is allowed
+    pub fn is_step_allowed(&self, step: u8) -> bool {
+        (step as usize) < self.tuning.num_steps() && self.allowed >> step & 1 == 1
+    }
+
+    fn allow_step(&mut self, step: u8) {
+        if (step as usize) < self.tuning.num_steps() {
+            self.allowed |= 1 << step;
+        }
+    }
+
+    /// `q.keep_one_allowed(ss)` allows the last of steps `ss` which is in the tuning if no step is allowed, or step zero
+    /// if none of them are
+    fn keep_one_allowed(&mut self, steps: impl DoubleEndedIterator<Item = u8>) {
+        if self.allowed == 0 {
+            let num_steps = self.tuning.num_steps();
+            let step = steps.rev().find(|s| (*s as usize) < num_steps).unwrap_or(0);
+            self.allow_step(step);
+        }
+    }
+
+    fn forbid_step(&mut self, step: u8) {
+        if (step as usize) < self.tuning.num_steps() {
+            self.allowed &= !(1 << step);
+        }
+    }
+
+    /// `q.set_scale(s, r)` allows exactly the notes of scale `s` with root note `r`, all other notes are forbidden
+    ///
+    /// Named scales are meant to be used with tunings that have 12 steps per period.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, Note, Scale};
+    /// let mut q = Quantizer::new();
+    /// q.set_scale(Scale::MinorPentatonic, Note::A);
+    ///
+    /// assert!(q.is_allowed(Note::A));
+    /// assert!(q.is_allowed(Note::C));
+    /// assert!(!q.is_allowed(Note::B));
+    /// ```
+    pub fn set_scale(&mut self, scale: Scale, root: Note) {
+        self.allowed = scale.mask(root) as u64;
+        self.scale_hint = Some((scale, root));
+    }
+
+    /// `q.scale()` is the named scale and root note matching the currently allowed notes, if there is one
+    ///
+    /// Some scales share the same notes, for example C major and A minor. If the allowed notes were last set with
+    /// `set_scale` and have not been changed since, that scale and root are returned. Otherwise the first match in
+    /// `Scale::ALL` with the lowest root note is returned.
+    ///
+    /// Tunings which don't have 12 steps per period never match a named scale.
+    pub fn scale(&self) -> Option<(Scale, Note)> {
+        if self.tuning.num_steps() != 12 {
+            return None;
+        }
+
+        if let Some((scale, root)) = self.scale_hint {
+            if scale.mask(root) as u64 == self.allowed {
+                return self.scale_hint;
+            }
+        }
+
+        Scale::ALL.iter().find_map(|scale| {
+            (0..12)
+                .map(Note::new)
+                .find(|root| scale.mask(*root) as u64 == self.allowed)
+                .map(|root| (*scale, root))
+        })
+    }
+
+    /// `q.save_preset()` is the allowed notes, scale, hysteresis, and rounding of the quantizer as a preset
+    ///
+    /// The tuning, range, and sample mode are not included in presets.
+    ///
+    /// Presets are `PRESET_SIZE` bytes long, and the format is stable so that saved presets may be loaded by future
+    /// versions of this crate. Multi-byte values are little endian. Version 1 presets are laid out as:
+    ///
+    /// - byte 0: the preset format version, `PRESET_VERSION`
+    /// - bytes 1 to 8: the allowed steps bitfield
+    /// - byte 9: the index of the named scale in `Scale::ALL`, or `0xFF` for none
+    /// - byte 10: the root note of the named scale
+    /// - bytes 11 to 14: the hysteresis as an `f32`
+    /// - byte 15: the rounding mode, 0 for nearest, 1 for floor, 2 for ceil
+    /// - byte 16: CRC-8 checksum of all the bytes before it
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, Note, Scale};
+    /// let mut q = Quantizer::new();
+    /// q.set_scale(Scale::Dorian, Note::D);
+    /// let preset = q.save_preset();
+    ///
+    /// let mut other = Quantizer::new();
+    /// other.load_preset(&preset).unwrap();
+    /// assert_eq!(other.scale(), Some((Scale::Dorian, Note::D)));
+    /// ```
+    pub fn save_preset(&self) -> [u8; PRESET_SIZE] {
+        let (scale_index, root) = match self.scale_hint {
+            Some((scale, root)) => (
+                Scale::ALL.iter().position(|s| *s == scale).unwrap_or(0) as u8,
+                root.0,
+            ),
+            None => (NO_SCALE, 0),
+        };
+
+        let mut preset = [0; PRESET_SIZE];
+        preset[0] = PRESET_VERSION;
+        preset[1..9].copy_from_slice(&self.allowed.to_le_bytes());
+        preset[9] = scale_index;
+        preset[10] = root;
+        preset[11..15].copy_from_slice(&self.hysteresis.to_le_bytes());
+        preset[15] = match self.rounding {
+            Rounding::Nearest => 0,
+            Rounding::Floor => 1,
+            Rounding::Ceil => 2,
+            Rounding::Truncate => 3,
+        };
+        preset[PRESET_SIZE - 1] = crc8(&preset[..PRESET_SIZE - 1]);
+
+        preset
+    }
+
+    /// `q.load_preset(p)` sets the allowed notes, scale, hysteresis, and rounding of the quantizer from preset `p`
+    ///
+    /// Allowed steps past the end of the current tuning are ignored.
+    ///
+    /// # Errors
+    ///
+    /// The quantizer is left unchanged if the preset is empty, the wrong size, from an unsupported version, has the
+    /// wrong checksum, has invalid values, or would leave no steps allowed in the current tuning.
+    pub fn load_preset(&mut self, preset: &[u8]) -> Result<(), PresetError> {
+        if preset.len() != PRESET_SIZE {
+            return Err(PresetError::WrongSize);
+        }
+        if preset.iter().all(|b| *b == ERASED_BYTE) {
+            return Err(PresetError::Empty);
+        }
+        if preset[0] != PRESET_VERSION {
+            return Err(PresetError::UnsupportedVersion);
+        }
+        if crc8(&preset[..PRESET_SIZE - 1]) != preset[PRESET_SIZE - 1] {
+            return Err(PresetError::BadChecksum);
+        }
+
+        let mut allowed_bytes = [0; 8];
+        allowed_bytes.copy_from_slice(&preset[1..9]);
+        let allowed = u64::from_le_bytes(allowed_bytes) & all_steps(self.tuning.num_steps());
+        if allowed == 0 {
+            return Err(PresetError::InvalidValue);
+        }
+
+        let scale_hint = match (preset[9], preset[10]) {
+            (NO_SCALE, _) => None,
+            (scale_index, root) if root <= 11 => Some((
+                *Scale::ALL
+                    .get(scale_index as usize)
+                    .ok_or(PresetError::InvalidValue)?,
+                Note::new(root),
+            )),
+            _ => return Err(PresetError::InvalidValue),
+        };
+
+        let mut hysteresis_bytes = [0; 4];
+        hysteresis_bytes.copy_from_slice(&preset[11..15]);
+        let hysteresis = f32::from_le_bytes(hysteresis_bytes);
+        if hysteresis.is_nan() {
+            return Err(PresetError::InvalidValue);
+        }
+
+        let rounding = match preset[15] {
+            0 => Rounding::Nearest,
+            1 => Rounding::Floor,
+            2 => Rounding::Ceil,
+            3 => Rounding::Truncate,
+            _ => return Err(PresetError::InvalidValue),
+        };
+
+        self.allowed = allowed;
+        self.scale_hint = scale_hint;
+        self.set_hysteresis(hysteresis);
+        self.rounding = rounding;
+
+        Ok(())
+    }
+}
+
+/// A bank of `SLOTS` quantizer presets is represented here.
+///
+/// Each slot holds the bytes of one preset, so the slots can be written to and read from EEPROM or flash as they are.
+/// Empty slots are filled with `0xFF`, the same as erased EEPROM and flash.
+pub struct PresetBank<const SLOTS: usize> {
+    slots: [[u8; PRESET_SIZE]; SLOTS],
+}
+
+#[allow(clippy::new_without_default)]
+impl<const SLOTS: usize> PresetBank<SLOTS> {
+    /// `PresetBank::new()` is a new preset bank with all slots empty
+    pub fn new() -> Self {
+        Self {
+            slots: [[ERASED_BYTE; PRESET_SIZE]; SLOTS],
+        }
+    }
+
+    /// `pb.store(n, q)` saves the state of quantizer `q` into slot `n`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::quantizer::{Quantizer, PresetBank, PresetError, Note, Scale};
+    /// let mut bank = PresetBank::<4>::new();
+    /// let mut q = Quantizer::new();
+    ///
+    /// q.set_scale(Scale::Blues, Note::E);
+    /// bank.store(2, &q).unwrap();
+    ///
+    /// q.set_scale(Scale::Major, Note::C);
+    /// bank.recall(2, &mut q).unwrap();
+    /// assert_eq!(q.scale(), Some((Scale::Blues, Note::E)));
+    ///
+    /// assert_eq!(bank.recall(0, &mut q), Err(PresetError::Empty));
+    /// ```
+    pub fn store(&mut self, slot: usize, quantizer: &Quantizer) -> Result<(), PresetError> {
+        *self.slots.get_mut(slot).ok_or(PresetError::NoSuchSlot)? = quantizer.save_preset();
+        Ok(())
+    }
+
+    /// `pb.recall(n, q)` loads the preset in slot `n` into quantizer `q`, see `Quantizer::load_preset`
+    pub fn recall(&self, slot: usize, quantizer: &mut Quantizer) -> Result<(), PresetError> {
+        quantizer.load_preset(self.slot(slot).ok_or(PresetError::NoSuchSlot)?)
+    }
+
+    /// `pb.clear(n)` empties slot `n`, slots that don't exist are ignored
+    pub fn clear(&mut self, slot: usize) {
+        if let Some(s) = self.slots.get_mut(slot) {
+            *s = [ERASED_BYTE; PRESET_SIZE];
+        }
+    }
+
+    /// `pb.is_empty(n)` is true iff slot `n` is empty or does not exist
+    pub fn is_empty(&self, slot: usize) -> bool {
+        match self.slot(slot) {
+            Some(s) => s.iter().all(|b| *b == ERASED_BYTE),
+            None => true,
+        }
+    }
+
+    /// `pb.slot(n)` is the bytes of slot `n`, for writing to storage, or `None` if the slot does not exist
+    pub fn slot(&self, slot: usize) -> Option<&[u8; PRESET_SIZE]> {
+        self.slots.get(slot)
+    }
+
+    /// `pb.set_slot(n, bs)` sets the bytes of slot `n` to `bs`, for restoring from storage
+    ///
+    /// The bytes are not checked until they are recalled.
+    pub fn set_slot(&mut self, slot: usize, bytes: [u8; PRESET_SIZE]) -> Result<(), PresetError> {
+        *self.slots.get_mut(slot).ok_or(PresetError::NoSuchSlot)? = bytes;
+        Ok(())
+    }
+}
+
+/// Errors which can happen when loading presets are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresetError {
+    /// The preset was not `PRESET_SIZE` bytes long
+    WrongSize,
+    /// The preset is erased, all bytes are `0xFF`
+    Empty,
+    /// The preset was saved in a format version this crate does not understand
+    UnsupportedVersion,
+    /// The checksum does not match, the preset is corrupted
+    BadChecksum,
+    /// The preset has a value which is out of range, or would leave no steps allowed
+    InvalidValue,
+    /// The preset bank does not have the requested slot
+    NoSuchSlot,
+}
+
+/// `crc8(bs)` is the CRC-8 checksum of bytes `bs`, with polynomial `0x07`
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, b| {
+        (0..8).fold(crc ^ b, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// `all_steps(n)` is a bitfield with the lowest `n` bits set
+fn all_steps(num_steps: usize) -> u64 {
+    if num_steps < MAX_STEPS_PER_PERIOD {
+        (1 << num_steps) - 1
+    } else {
+        u64::MAX
+    }
+}
+
+/// Selection modes are represented here
+///
+/// - `Deterministic` means that the input is always converted to the step chosen by the rounding mode
+///
+/// - `Weighted` means that the input is converted to either the step chosen by the rounding mode, or the allowed steps
+///   just above and below it, picked at random using the step weights
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionMode {
+    Deterministic,
+    Weighted,
+}
+
+/// Rounding modes are represented here
+///
+/// - `Truncate` means that inputs are converted to the step just below them if it is allowed, otherwise to the closest
+///   allowed step. This is the default, and is how the quantizer has always converted inputs
+///
+/// - `Nearest` means that inputs are converted to the closest allowed step
+///
+/// - `Floor` means that inputs are converted to the highest allowed step which is not above the input
+///
+/// - `Ceil` means that inputs are converted to the lowest allowed step which is not below the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Truncate,
+    Nearest,
+    Floor,
+    Ceil,
+}
+
+/// Sample modes are represented here
+///
+/// - `Continuous` means that every conversion follows the input
+///
+/// - `Clocked` means that conversions only follow the input after a trigger, and hold the previous conversion
+///   otherwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleMode {
+    Continuous,
+    Clocked,
+}
+
+/// Note names are represented here, the quantizer can allow and forbid various notes from being converted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Note(u8);
+
+impl Note {
+    pub const C: Self = Self::new(0);
+    pub const CSHARP: Self = Self::new(1);
+    pub const D: Self = Self::new(2);
+    pub const DSHARP: Self = Self::new(3);
+    pub const E: Self = Self::new(4);
+    pub const F: Self = Self::new(5);
+    pub const FSHARP: Self = Self::new(6);
+    pub const G: Self = Self::new(7);
+    pub const GSHARP: Self = Self::new(8);
+    pub const A: Self = Self::new(9);
+    pub const ASHARP: Self = Self::new(10);
+    pub const B: Self = Self::new(11);
+
+    /// `Note::new(n)` is a new note from `n` clamped to `[0..11]`
+    pub const fn new(n: u8) -> Self {
+        Self(if n <= 11 { n } else { 11 })
+    }
+}
+
+impl From<u8> for Note {
+    fn from(n: u8) -> Self {
+        Self::new(n)
+    }
+}
+
+impl From<Note> for u8 {
+    fn from(n: Note) -> Self {
+        n.0
+    }
+}
+
+/// Named scales are represented here
+///
+/// Each scale is a set of notes relative to a root note, the same scale may be played from any of the 12 roots.
+///
+/// The seven diatonic modes are included, `Major` is the Ionian mode and `Minor` is the Aeolian (natural minor) mode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Minor,
+    Locrian,
+    HarmonicMinor,
+    MelodicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    WholeTone,
+    DiminishedWholeHalf,
+    DiminishedHalfWhole,
+    Augmented,
+    PhrygianDominant,
+    HungarianMinor,
+    DoubleHarmonic,
+    Hirajoshi,
+    In,
+}
+
+impl Scale {
+    /// Every named scale, in the order they are searched when matching allowed notes to a scale
+    pub const ALL: [Self; 22] = [
+        Self::Chromatic,
+        Self::Major,
+        Self::Dorian,
+        Self::Phrygian,
+        Self::Lydian,
+        Self::Mixolydian,
+        Self::Minor,
+        Self::Locrian,
+        Self::HarmonicMinor,
+        Self::MelodicMinor,
+        Self::MajorPentatonic,
+        Self::MinorPentatonic,
+        Self::Blues,
+        Self::WholeTone,
+        Self::DiminishedWholeHalf,
+        Self::DiminishedHalfWhole,
+        Self::Augmented,
+        Self::PhrygianDominant,
+        Self::HungarianMinor,
+        Self::DoubleHarmonic,
+        Self::Hirajoshi,
+        Self::In,
+    ];
+
+    /// `s.intervals()` is the scale as a bitfield of semitones above the root, bit zero is the root itself
+    pub const fn intervals(self) -> u16 {
+        match self {
+            Self::Chromatic => 0b1111_1111_1111,
+            Self::Major => 0b1010_1011_0101,
+            Self::Dorian => 0b0110_1010_1101,
+            Self::Phrygian => 0b0101_1010_1011,
+            Self::Lydian => 0b1010_1101_0101,
+            Self::Mixolydian => 0b0110_1011_0101,
+            Self::Minor => 0b0101_1010_1101,
+            Self::Locrian => 0b0101_0110_1011,
+            Self::HarmonicMinor => 0b1001_1010_1101,
+            Self::MelodicMinor => 0b1010_1010_1101,
+            Self::MajorPentatonic => 0b0010_1001_0101,
+            Self::MinorPentatonic => 0b0100_1010_1001,
+            Self::Blues => 0b0100_1110_1001,
+            Self::WholeTone => 0b0101_0101_0101,
+            Self::DiminishedWholeHalf => 0b1011_0110_1101,
+            Self::DiminishedHalfWhole => 0b0110_1101_1011,
+            Self::Augmented => 0b1001_1001_1001,
+            Self::PhrygianDominant => 0b0101_1011_0011,
+            Self::HungarianMinor => 0b1001_1100_1101,
+            Self::DoubleHarmonic => 0b1001_1011_0011,
+            Self::Hirajoshi => 0b0001_1000_1101,
+            Self::In => 0b0001_1010_0011,
+        }
+    }
+
+    /// `s.mask(r)` is the scale with root note `r` as a bitfield of allowed notes, bit zero is C
+    pub const fn mask(self, root: Note) -> u16 {
+        let intervals = self.intervals();
+        let r = root.0 as u16;
+        ((intervals << r) | (intervals >> (12 - r))) & 0b1111_1111_1111
+    }
+}
+
+pub const NUM_NOTES_PER_OCTAVE: f32 = 12.0_f32;
+
+/// The width of each bucket for the semitones.
+pub const SEMITONE_WIDTH: f32 = 1.0_f32 / NUM_NOTES_PER_OCTAVE;
+pub const HALF_SEMITONE_WIDTH: f32 = SEMITONE_WIDTH / 2.0_f32;
+
+/// Hysteresis provides some noise immunity and prevents oscillations near transition regions.
+///
+/// The default hysteresis is 10% of the width of a step.
+pub const DEFAULT_HYSTERESIS: f32 = 0.1_f32;
+
+/// The largest hysteresis allowed, as a fraction of the width of a step
+pub const MAX_HYSTERESIS: f32 = 0.5_f32;
+
+/// The default input range in volts
+pub const DEFAULT_V_MIN: f32 = 0.0_f32;
+pub const DEFAULT_V_MAX: f32 = 10.0_f32;
+
+/// The smallest volts per octave scaling allowed
+pub const MIN_VOLTS_PER_OCTAVE: f32 = 0.01_f32;
+
+/// The largest step weight, all steps have this weight by default
+pub const MAX_WEIGHT: u8 = u8::MAX;
+
+/// The random number generator seed used until another is set
+const DEFAULT_SEED: u32 = 0x1234_5678;
+
+/// The current preset format version
+pub const PRESET_VERSION: u8 = 1;
+
+/// The size of a preset in bytes
+pub const PRESET_SIZE: usize = 17;
+
+/// The scale index used in presets when there is no named scale
+const NO_SCALE: u8 = 0xFF;
+
+/// The value of erased EEPROM and flash, used to mark empty presets
+const ERASED_BYTE: u8 = 0xFF;
+
+/// The lowest and highest step numbers the quantizer converts to, so that note numbers always fit in an `i16`
+const MIN_NOTE_NUM: i32 = i16::MIN as i32;
+const MAX_NOTE_NUM: i32 = i16::MAX as i32;
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vin_0_is_note_num_zero_with_all_allowed() {
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(0.0).note_num, 0);
+    }
+
+    #[test]
+    fn vin_point_08333_is_note_num_1_with_all_allowed() {
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(1. / 12.).note_num, 1);
+    }
+
+    #[test]
+    fn vin_1_is_note_num_12_with_all_allowed() {
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(1.).note_num, 12);
+    }
+
+    #[test]
+    fn vin_2_point_08333_is_note_num_25_with_all_allowed() {
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(2. + 1. / 12.).note_num, 25);
+    }
+
+    #[test]
+    fn when_C_is_forbidden_vin_0_is_1() {
+        let mut q = Quantizer::new();
+        q.forbid(&[Note::C]);
+        assert_eq!(q.convert(0.0).note_num, 1);
+    }
+
+    #[test]
+    fn when_only_B_is_allowed_vin_0_is_11() {
+        let mut q = Quantizer::new();
+        q.forbid(&[
+            Note::C,
+            Note::CSHARP,
+            Note::D,
+            Note::DSHARP,
+            Note::E,
+            Note::F,
+            Note::FSHARP,
+            Note::G,
+            Note::GSHARP,
+            Note::A,
+            Note::ASHARP,
+            // Note::B,
+        ]);
+        assert_eq!(q.convert(0.0).note_num, 11);
+    }
+
+    #[test]
+    fn when_only_Dsharp_is_allowed_vin_8_12ths_is_3() {
+        let mut q = Quantizer::new();
+        q.forbid(&[
+            Note::C,
+            Note::CSHARP,
+            Note::D,
+            // Note::Dsharp,
+            Note::E,
+            Note::F,
+            Note::FSHARP,
+            Note::G,
+            Note::GSHARP,
+            Note::A,
+            Note::ASHARP,
+            Note::B,
+        ]);
+        // it picks the D# in octave zero
+        assert_eq!(q.convert(8. / 12.).note_num, 3);
+    }
+
+    #[test]
+    fn when_only_Dsharp_is_allowed_vin_10_12ths_is_15() {
+        let mut q = Quantizer::new();
+        q.forbid(&[
+            Note::C,
+            Note::CSHARP,
+            Note::D,
+            // Note::Dsharp,
+            Note::E,
+            Note::F,
+            Note::FSHARP,
+            Note::G,
+            Note::GSHARP,
+            Note::A,
+            Note::ASHARP,
+            Note::B,
+        ]);
+        // it picks the D# in octave 1
+        assert_eq!(q.convert(10. / 12.).note_num, 15);
+    }
+
+    #[test]
+    fn can_not_forbid_every_note() {
+        let mut q = Quantizer::new();
+        // try to forbid every note
+        q.forbid(&[
+            Note::C,
+            Note::CSHARP,
+            Note::D,
+            Note::DSHARP,
+            Note::E,
+            Note::F,
+            Note::FSHARP,
+            Note::G,
+            Note::GSHARP,
+            Note::A,
+            Note::ASHARP,
+            Note::B,
+        ]);
+        // B is still left, because it is the last one we tried to forbid
+        assert_eq!(q.convert(0.5).note_num, 11);
+    }
+
+    const HYSTERESIS: f32 = SEMITONE_WIDTH * DEFAULT_HYSTERESIS;
+
+    #[test]
+    fn hysteresis_widens_window() {
+        let mut q = Quantizer::new();
 
         // register a conversion with note number 1
         assert_eq!(q.convert(1. / 12. + HALF_SEMITONE_WIDTH * 0.99).note_num, 1);
 
-        // it is now a little harder to get back out of 1, due to hysteresis
-        assert_eq!(q.convert(1. / 12. - HYSTERESIS * 0.99).note_num, 1);
-        assert_eq!(
-            q.convert(1. / 12. + SEMITONE_WIDTH + HYSTERESIS * 0.99)
-                .note_num,
-            1
-        );
+        // it is now a little harder to get back out of 1, due to hysteresis
+        assert_eq!(q.convert(1. / 12. - HYSTERESIS * 0.99).note_num, 1);
+        assert_eq!(
+            q.convert(1. / 12. + SEMITONE_WIDTH + HYSTERESIS * 0.99)
+                .note_num,
+            1
+        );
+
+        // starting from scratch the same input values map to the below and above semitones
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(1. / 12. - HYSTERESIS * 0.99).note_num, 0);
+
+        let mut q = Quantizer::new();
+        assert_eq!(
+            q.convert(1. / 12. + SEMITONE_WIDTH + HYSTERESIS * 0.99)
+                .note_num,
+            2
+        );
+    }
+
+    #[test]
+    fn nearest_hysteresis_widens_window() {
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Nearest);
+
+        // register a conversion with note number 1
+        assert_eq!(q.convert(1. / 12.).note_num, 1);
+
+        // it is now a little harder to get back out of 1, due to hysteresis
+        assert_eq!(
+            q.convert(1. / 12. - HALF_SEMITONE_WIDTH - HYSTERESIS * 0.99)
+                .note_num,
+            1
+        );
+        assert_eq!(
+            q.convert(1. / 12. + HALF_SEMITONE_WIDTH + HYSTERESIS * 0.99)
+                .note_num,
+            1
+        );
+
+        // starting from scratch the same input values map to the below and above semitones
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Nearest);
+        assert_eq!(
+            q.convert(1. / 12. - HALF_SEMITONE_WIDTH - HYSTERESIS * 0.99)
+                .note_num,
+            0
+        );
+
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Nearest);
+        assert_eq!(
+            q.convert(1. / 12. + HALF_SEMITONE_WIDTH + HYSTERESIS * 0.99)
+                .note_num,
+            2
+        );
+    }
+
+    #[test]
+    fn C_major_scale_allows_the_white_keys() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Major, Note::C);
+        assert_eq!(q.allowed, 0b1010_1011_0101);
+    }
+
+    #[test]
+    fn scale_root_rotates_the_mask() {
+        // D dorian has the same notes as C major
+        assert_eq!(Scale::Dorian.mask(Note::D), Scale::Major.mask(Note::C));
+        // B locrian has the same notes as C major, and wraps around the octave
+        assert_eq!(Scale::Locrian.mask(Note::B), Scale::Major.mask(Note::C));
+    }
+
+    #[test]
+    fn every_scale_includes_its_root() {
+        for scale in Scale::ALL {
+            for root in 0..12 {
+                let root = Note::new(root);
+                assert_eq!(scale.mask(root) >> root.0 & 1, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn scale_notes_quantize_as_expected() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::WholeTone, Note::CSHARP);
+        // C is forbidden, C# is the nearest allowed note
+        assert_eq!(q.convert(0.0).note_num, 1);
+    }
+
+    #[test]
+    fn scale_query_remembers_the_set_scale() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Minor, Note::A);
+        assert_eq!(q.scale(), Some((Scale::Minor, Note::A)));
+    }
+
+    #[test]
+    fn scale_query_finds_hand_programmed_scales() {
+        let mut q = Quantizer::new();
+        q.forbid(&[
+            Note::CSHARP,
+            Note::DSHARP,
+            Note::FSHARP,
+            Note::GSHARP,
+            Note::ASHARP,
+        ]);
+        assert_eq!(q.scale(), Some((Scale::Major, Note::C)));
+
+        q.forbid(&[Note::B]);
+        assert_eq!(q.scale(), None);
+    }
+
+    #[test]
+    fn scale_query_forgets_the_set_scale_after_editing() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Minor, Note::A);
+        q.allow(&[Note::GSHARP]);
+        q.forbid(&[Note::G]);
+        assert_eq!(q.scale(), Some((Scale::HarmonicMinor, Note::A)));
+    }
+
+    #[test]
+    fn all_notes_allowed_is_chromatic() {
+        let q = Quantizer::new();
+        assert_eq!(q.scale(), Some((Scale::Chromatic, Note::C)));
+    }
+
+    #[test]
+    fn nineteen_edo_steps() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(19));
+
+        assert_eq!(q.convert(0.0).note_num, 0);
+        assert_eq!(q.convert(5.0 / 19.0).note_num, 5);
+        assert_eq!(q.convert(1.0 + 5.0 / 19.0).note_num, 24);
+    }
+
+    #[test]
+    fn thirty_one_edo_note_numbers_go_past_255() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(31));
+
+        assert_eq!(q.convert(9.0).note_num, 279);
+        assert!(is_almost(q.convert(9.0).stairstep, 9.0, 0.000_01));
+    }
+
+    #[test]
+    fn forbidden_microtonal_steps_are_skipped() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(24));
+        // allow only the quarter tones between the semitones
+        q.forbid_steps(&[0, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22]);
+
+        assert!(!q.is_step_allowed(0));
+        assert!(q.is_step_allowed(1));
+        assert_eq!(q.convert(1.8 / 24.0).note_num, 1);
+    }
+
+    #[test]
+    fn just_intonation_stairstep_is_the_ratio() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::from_ratios(&[(9, 8), (5, 4), (3, 2), (5, 3), (2, 1)]).unwrap());
+
+        // the just fifth is the third step
+        let conversion = q.convert(0.59);
+        assert_eq!(conversion.note_num, 3);
+        assert!(is_almost(conversion.stairstep, 0.584_962_5, 0.000_001));
+    }
+
+    #[test]
+    fn steps_past_the_end_of_the_tuning_are_ignored() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(5));
+        q.forbid(&[Note::B]);
+        assert!(!q.is_allowed(Note::B));
+        assert!(q.is_step_allowed(4));
+        assert!(!q.is_step_allowed(5));
+    }
+
+    #[test]
+    fn can_not_forbid_every_step() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(3));
+        q.forbid_steps(&[0, 1, 2]);
+        assert!(q.is_step_allowed(2));
+    }
+
+    #[test]
+    fn can_not_forbid_every_step_with_steps_past_the_end_of_the_tuning() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(5));
+        q.forbid(&[
+            Note::C,
+            Note::CSHARP,
+            Note::D,
+            Note::DSHARP,
+            Note::E,
+            Note::B,
+        ]);
+        assert!(q.is_step_allowed(4));
+        assert!((0..4).all(|s| !q.is_step_allowed(s)));
+
+        q.set_tuning(Tuning::equal_temperament(5));
+        q.forbid_steps(&[0, 1, 2, 3, 4, 40]);
+        assert!(q.is_step_allowed(4));
+    }
+
+    #[test]
+    fn non_twelve_step_tunings_have_no_named_scale() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(19));
+        assert_eq!(q.scale(), None);
+    }
+
+    #[test]
+    fn zero_hysteresis_changes_right_at_the_edge() {
+        let mut q = Quantizer::new();
+        q.set_hysteresis(0.0);
+        q.set_rounding(Rounding::Nearest);
+        assert_eq!(q.convert(1. / 12.).note_num, 1);
+        assert_eq!(q.convert(1. / 12. + HALF_SEMITONE_WIDTH * 1.01).note_num, 2);
+        assert_eq!(q.convert(1. / 12. + HALF_SEMITONE_WIDTH * 0.99).note_num, 1);
+    }
+
+    #[test]
+    fn hysteresis_is_clamped() {
+        let mut q = Quantizer::new();
+        q.set_hysteresis(-1.0);
+        assert_eq!(q.hysteresis(), 0.0);
+        q.set_hysteresis(2.0);
+        assert_eq!(q.hysteresis(), MAX_HYSTERESIS);
+    }
 
-        // starting from scratch the same input values map to the below and above semitones
+    #[test]
+    fn ceil_rounds_up_to_the_next_allowed_note() {
         let mut q = Quantizer::new();
-        assert_eq!(q.convert(1. / 12. - HYSTERESIS * 0.99).note_num, 0);
+        q.set_rounding(Rounding::Ceil);
+        q.set_scale(Scale::Major, Note::C);
+        // a little above E goes up to F
+        assert_eq!(q.convert(4.1 / 12.).note_num, 5);
+        // right on E stays on E
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Ceil);
+        assert_eq!(q.convert(4. / 12.).note_num, 4);
+    }
+
+    #[test]
+    fn floor_rounds_down_to_the_previous_allowed_note() {
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Floor);
+        q.set_scale(Scale::Major, Note::C);
+        // just below B goes down to A
+        assert_eq!(q.convert(10.9 / 12.).note_num, 9);
+        // the previous allowed note can be in the octave below
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Floor);
+        q.set_scale(Scale::Major, Note::D);
+        assert_eq!(q.convert(1.0 + 0.5 / 12.).note_num, 11);
+    }
+
+    #[test]
+    fn floor_falls_back_to_nearest_at_zero() {
+        let mut q = Quantizer::new();
+        q.set_rounding(Rounding::Floor);
+        q.forbid(&[Note::C]);
+        assert_eq!(q.convert(0.0).note_num, 1);
+    }
+
+    #[test]
+    fn transpose_degrees_stays_in_the_scale() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::MinorPentatonic, Note::A);
+
+        let a = q.convert(9.0 / 12.0);
+        assert_eq!(a.note_num, 9);
+
+        // A C D E G A
+        assert_eq!(q.transpose_degrees(a, 1).note_num, 12);
+        assert_eq!(q.transpose_degrees(a, 5).note_num, 21);
+        assert_eq!(q.transpose_degrees(a, -1).note_num, 7);
+        assert_eq!(q.transpose_degrees(a, 0).note_num, 9);
+    }
+
+    #[test]
+    fn transposed_stairstep_matches_note_num() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Major, Note::C);
+        let conversion = q.convert(1.0 + 0.1 / 12.0);
+        let transposed = q.transpose_degrees(conversion, 4);
+
+        assert_eq!(transposed.note_num, 19);
+        assert!(is_almost(transposed.stairstep, 19.0 / 12.0, 0.000_01));
+        assert_eq!(transposed.fraction, conversion.fraction);
+    }
+
+    #[test]
+    fn transpose_degrees_stops_at_the_ends_of_the_range() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::MajorPentatonic, Note::D);
+
+        // D is the lowest allowed note
+        let d = q.convert(2.0 / 12.0);
+        assert_eq!(q.transpose_degrees(d, -3).note_num, 2);
+
+        let top = q.convert(DEFAULT_V_MAX);
+        let transposed = q.transpose_degrees(top, 100);
+        assert!(transposed.note_num as i32 <= q.max_step());
+        assert!(q.is_step_allowed(q.step_in_period(transposed.note_num as i32)));
+    }
+
+    #[test]
+    fn transpose_steps_is_chromatic() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Major, Note::C);
+        let c = q.convert(1.0);
+
+        // C# is not in the scale, but chromatic transposition goes there anyway
+        assert_eq!(q.transpose_steps(c, 1).note_num, 13);
+        assert_eq!(q.transpose_steps(c, -100).note_num, 0);
+    }
+
+    #[test]
+    fn shift_octaves_uses_the_period() {
+        let mut q = Quantizer::new();
+        q.set_tuning(Tuning::equal_temperament(19));
+        let conversion = q.convert(1.0);
+        let shifted = q.shift_octaves(conversion, 2);
+
+        assert_eq!(shifted.note_num, 57);
+        assert!(is_almost(shifted.stairstep, 3.0, 0.000_01));
+    }
+
+    #[test]
+    fn negative_inputs_have_negative_note_nums() {
+        let mut q = Quantizer::new();
+        q.set_range(-5.0, 5.0);
+
+        let conversion = q.convert(-1.0 - 1.0 / 12.0);
+        assert_eq!(conversion.note_num, -13);
+        assert!(is_almost(conversion.stairstep, -13.0 / 12.0, 0.000_01));
+
+        // clamped to the bottom of the range
+        assert_eq!(q.convert(-8.0).note_num, -60);
+        assert_eq!(q.convert(8.0).note_num, 60);
+    }
+
+    #[test]
+    fn default_range_clamps_negative_inputs_to_zero() {
+        let mut q = Quantizer::new();
+        assert_eq!(q.convert(-1.0).note_num, 0);
+    }
+
+    #[test]
+    fn range_is_ordered() {
+        let mut q = Quantizer::new();
+        q.set_range(5.0, -5.0);
+        assert_eq!(q.range(), (-5.0, 5.0));
+    }
+
+    #[test]
+    fn negative_forbidden_notes_find_the_nearest_allowed() {
+        let mut q = Quantizer::new();
+        q.set_range(-5.0, 5.0);
+        q.set_scale(Scale::Major, Note::C);
+        // C# below zero volts is forbidden, the nearest allowed note is D
+        assert_eq!(q.convert(-11.0 / 12.0 + 0.2 / 12.0).note_num, -10);
+    }
+
+    #[test]
+    fn volts_per_octave_scales_input_and_stairstep() {
+        let mut q = Quantizer::new();
+        q.set_volts_per_octave(1.2);
+
+        // a fifth above the first octave
+        let conversion = q.convert(1.2 + 0.7);
+        assert_eq!(conversion.note_num, 19);
+        assert!(is_almost(conversion.stairstep, 1.9, 0.000_1));
+        assert!(is_almost(
+            conversion.stairstep + conversion.fraction,
+            1.9,
+            0.000_01
+        ));
+
+        // the range is still in volts
+        assert_eq!(q.convert(DEFAULT_V_MAX).note_num, 100);
+    }
+
+    #[test]
+    fn dense_tunings_keep_note_numbers_in_range() {
+        let mut q = Quantizer::new();
+        // 64 steps of one cent each
+        let cents: [f32; 64] = core::array::from_fn(|i| (i + 1) as f32);
+        q.set_tuning(Tuning::from_cents(&cents).unwrap());
+        q.set_volts_per_octave(MIN_VOLTS_PER_OCTAVE);
+        q.set_range(-1_000.0, 1_000.0);
+
+        let top = q.convert(1_000.0);
+        assert_eq!(top.note_num, i16::MAX);
+        assert_eq!(q.transpose_steps(top, 100).note_num, i16::MAX);
+        assert_eq!(q.convert(-1_000.0).note_num, i16::MIN);
+    }
+
+    #[test]
+    fn transpose_goes_below_zero_with_a_negative_range() {
+        let mut q = Quantizer::new();
+        q.set_range(-5.0, 5.0);
+        let c = q.convert(0.0);
+        assert_eq!(q.shift_octaves(c, -2).note_num, -24);
+        assert!(is_almost(q.shift_octaves(c, -2).stairstep, -2.0, 0.000_01));
+    }
+
+    #[test]
+    fn note_changed_is_only_set_once_per_note() {
+        let mut q = Quantizer::new();
+        assert!(q.convert(0.0).note_changed);
+        assert!(!q.convert(0.01).note_changed);
+        assert!(q.convert(0.5).note_changed);
+        assert!(!q.convert(0.5).note_changed);
+        assert!(!q.convert(0.5 + HALF_SEMITONE_WIDTH * 0.5).note_changed);
+    }
+
+    #[test]
+    fn clocked_mode_holds_until_triggered() {
+        let mut q = Quantizer::new();
+        q.set_sample_mode(SampleMode::Clocked);
+
+        let first = q.convert(1.0);
+        assert_eq!(first.note_num, 12);
+
+        let held = q.convert(2.0);
+        assert_eq!(held.note_num, 12);
+        assert_eq!(held.fraction, first.fraction);
+        assert!(!held.note_changed);
+
+        q.trigger();
+        q.trigger();
+        assert_eq!(q.convert(2.0).note_num, 24);
+
+        // the two triggers only counted once
+        assert_eq!(q.convert(3.0).note_num, 24);
+    }
+
+    #[test]
+    fn continuous_mode_ignores_triggers() {
+        let mut q = Quantizer::new();
+        q.trigger();
+        assert_eq!(q.convert(1.0).note_num, 12);
+        assert_eq!(q.convert(2.0).note_num, 24);
+    }
+
+    #[test]
+    fn deterministic_mode_ignores_weights() {
+        let mut q = Quantizer::new();
+        q.set_weight(Note::C, 0);
+        assert_eq!(q.convert(0.0).note_num, 0);
+    }
+
+    #[test]
+    fn weighted_mode_picks_nearby_allowed_notes() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Major, Note::C);
+        q.set_selection_mode(SelectionMode::Weighted);
+
+        let mut counts = [0; 3];
+        for i in 0..300 {
+            // alternate between two notes so that a new pick is made each time
+            let v = if i % 2 == 0 { 1.0 + 4.0 / 12.0 } else { 0.0 };
+            match q.convert(v).note_num {
+                14 => counts[0] += 1,
+                16 => counts[1] += 1,
+                17 => counts[2] += 1,
+                0 | 2 | 11 => (),
+                n => panic!("unexpected note {}", n),
+            }
+        }
+        // D, E, and F are all picked sometimes
+        assert!(counts.iter().all(|c| 20 < *c));
+    }
+
+    #[test]
+    fn zero_weight_notes_are_never_picked() {
+        let mut q = Quantizer::new();
+        q.set_selection_mode(SelectionMode::Weighted);
+        q.set_weight(Note::CSHARP, 0);
+        q.set_weight(Note::DSHARP, 0);
+
+        for i in 0..100 {
+            let v = if i % 2 == 0 { 2.0 / 12.0 } else { 1.0 };
+            let note_num = q.convert(v).note_num;
+            assert!(note_num != 1 && note_num != 3);
+        }
+
+        // if every nearby weight is zero the rounded note is used
+        q.set_weight(Note::D, 0);
+        assert_eq!(q.convert(2.0 / 12.0).note_num, 2);
+    }
+
+    #[test]
+    fn weighted_mode_holds_the_pick_while_the_input_stays_put() {
+        let mut q = Quantizer::new();
+        q.set_selection_mode(SelectionMode::Weighted);
+        let first = q.convert(0.5).note_num;
+        for _ in 0..100 {
+            assert_eq!(q.convert(0.5 + 0.01 / 12.0).note_num, first);
+        }
+    }
+
+    #[test]
+    fn clocked_weighted_mode_picks_on_each_trigger() {
+        let mut q = Quantizer::new();
+        q.set_selection_mode(SelectionMode::Weighted);
+        q.set_sample_mode(SampleMode::Clocked);
+
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            q.trigger();
+            let note_num = q.convert(0.5).note_num;
+            seen[(note_num - 5) as usize] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    #[test]
+    fn same_seed_same_picks() {
+        let picks = |seed| {
+            let mut q = Quantizer::new();
+            q.seed(seed);
+            q.set_selection_mode(SelectionMode::Weighted);
+            q.set_sample_mode(SampleMode::Clocked);
+            let mut picks = [0; 16];
+            for p in picks.iter_mut() {
+                q.trigger();
+                *p = q.convert(0.5).note_num;
+            }
+            picks
+        };
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(43));
+        assert_eq!(picks(0), picks(DEFAULT_SEED));
+    }
+
+    #[test]
+    fn presets_round_trip() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::HungarianMinor, Note::GSHARP);
+        q.set_hysteresis(0.25);
+        q.set_rounding(Rounding::Ceil);
+        let preset = q.save_preset();
+
+        let mut other = Quantizer::new();
+        other.load_preset(&preset).unwrap();
+        assert_eq!(other.allowed, q.allowed);
+        assert_eq!(other.scale(), Some((Scale::HungarianMinor, Note::GSHARP)));
+        assert_eq!(other.hysteresis(), 0.25);
+        assert_eq!(other.rounding(), Rounding::Ceil);
+    }
+
+    #[test]
+    fn preset_format_is_stable() {
+        let mut q = Quantizer::new();
+        q.set_scale(Scale::Major, Note::C);
+        let preset = q.save_preset();
+
+        assert_eq!(preset[0], PRESET_VERSION);
+        assert_eq!(&preset[1..9], &[0xB5, 0x0A, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(preset[9], 1);
+        assert_eq!(preset[10], 0);
+        assert_eq!(&preset[11..15], &DEFAULT_HYSTERESIS.to_le_bytes());
+        assert_eq!(preset[15], 3);
+        assert_eq!(preset[16], crc8(&preset[..16]));
+    }
 
+    #[test]
+    fn hand_programmed_notes_have_no_scale_in_presets() {
+        let mut q = Quantizer::new();
+        q.forbid(&[Note::CSHARP]);
+        let preset = q.save_preset();
+        assert_eq!(preset[9], NO_SCALE);
+
+        let mut other = Quantizer::new();
+        other.set_scale(Scale::Blues, Note::A);
+        other.load_preset(&preset).unwrap();
+        assert!(!other.is_allowed(Note::CSHARP));
+        assert!(other.is_allowed(Note::D));
+    }
+
+    #[test]
+    fn bad_presets_are_rejected_and_leave_the_quantizer_unchanged() {
         let mut q = Quantizer::new();
+        q.set_scale(Scale::Minor, Note::A);
+        let good = q.save_preset();
+
+        let mut other = Quantizer::new();
+        assert_eq!(other.load_preset(&good[..4]), Err(PresetError::WrongSize));
         assert_eq!(
-            q.convert(1. / 12. + SEMITONE_WIDTH + HYSTERESIS * 0.99)
-                .note_num,
-            2
+            other.load_preset(&[0xFF; PRESET_SIZE]),
+            Err(PresetError::Empty)
+        );
+
+        let mut corrupt = good;
+        corrupt[3] ^= 0x01;
+        assert_eq!(other.load_preset(&corrupt), Err(PresetError::BadChecksum));
+
+        let mut future = good;
+        future[0] = PRESET_VERSION + 1;
+        future[16] = crc8(&future[..16]);
+        assert_eq!(
+            other.load_preset(&future),
+            Err(PresetError::UnsupportedVersion)
+        );
+
+        let mut bad_rounding = good;
+        bad_rounding[15] = 7;
+        bad_rounding[16] = crc8(&bad_rounding[..16]);
+        assert_eq!(
+            other.load_preset(&bad_rounding),
+            Err(PresetError::InvalidValue)
         );
+
+        assert_eq!(other.scale(), Some((Scale::Chromatic, Note::C)));
+    }
+
+    #[test]
+    fn preset_bank_slots() {
+        let mut bank = PresetBank::<2>::new();
+        let q = Quantizer::new();
+
+        assert!(bank.is_empty(0));
+        bank.store(0, &q).unwrap();
+        assert!(!bank.is_empty(0));
+        assert_eq!(bank.store(2, &q), Err(PresetError::NoSuchSlot));
+
+        // slots can be copied out to storage and back in
+        let bytes = *bank.slot(0).unwrap();
+        let mut restored = PresetBank::<2>::new();
+        restored.set_slot(1, bytes).unwrap();
+        let mut other = Quantizer::new();
+        assert_eq!(restored.recall(1, &mut other), Ok(()));
+
+        bank.clear(0);
+        assert!(bank.is_empty(0));
     }
 
     #[test]
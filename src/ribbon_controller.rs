@@ -118,7 +118,7 @@ impl<const BUFFER_CAPACITY: usize> RibbonController<BUFFER_CAPACITY> {
     /// * `softpot_ohms` - The end-to-end resistance of the softpot used, typically 10k or 20k
     ///
     /// * `dropper_resistor_ohms` - The value of the resistor which sits between the top of the softpot and the positive
-    /// voltage reference.
+    ///   voltage reference.
     ///
     /// * `pullup_resistor_ohms` - The value of the wiper pullup reistor, shoudl be at least 10x softpot_ohms or larger
     pub fn new(
@@ -150,8 +150,8 @@ impl<const BUFFER_CAPACITY: usize> RibbonController<BUFFER_CAPACITY> {
     /// # Arguments
     ///
     /// * `raw_adc_value` - the raw ADC signal to poll in `[0.0, 1.0]`, represents the finger position on the ribbon.
-    /// Inputs outside of the range `[0.0, 1.0]` are undefined.
-    /// Note that a small portion of the range at the top near +1.0 is expected to be "eaten" by the series resistor
+    ///   Inputs outside of the range `[0.0, 1.0]` are undefined.
+    ///   Note that a small portion of the range at the top near +1.0 is expected to be "eaten" by the series resistor
     pub fn poll(&mut self, raw_adc_value: f32) {
         let user_is_pressing_ribbon = raw_adc_value < self.finger_press_high_boundary;
 
@@ -295,7 +295,7 @@ mod tests {
 
     /// `test_ribbon()` is a basic ribbon controller for testing
     fn test_ribbon() -> RibbonController<RIBBON_BUFF_CAPACITY> {
-        RibbonController::new(SAMPLE_RATE as f32, 20E3, 820.0, 1E6)
+        RibbonController::new(SAMPLE_RATE, 20E3, 820.0, 1E6)
     }
 
     // a bit glass-boxy, but hard to test otherwise, hand calculated by inspecting the code
@@ -0,0 +1,506 @@
+//! # Scala tuning files
+//!
+//! [Scala](https://www.huygens-fokker.org/scala/) is a popular program for experimenting with musical tunings, and its
+//! file formats are a common way to share tunings between synthesizers.
+//!
+//! Two file types are supported:
+//!
+//! - `.scl` scale files describe the pitches of a scale, these are parsed into a `Tuning` which may be used by the
+//!   quantizer
+//!
+//! - `.kbm` keyboard mapping files describe how MIDI notes are mapped onto the degrees of a scale, and which note is
+//!   tuned to which frequency. These are parsed into a `KeyboardMapping`, which converts MIDI note numbers into pitch.
+//!
+//! The parsers work directly on the text of the files and do not need to allocate.
+
+use heapless::Vec;
+
+use crate::{
+    tuning::{ratio_to_octaves, Tuning, TuningError, CENTS_PER_OCTAVE, MAX_STEPS_PER_PERIOD},
+    utils::*,
+};
+
+/// `parse_scl(s)` is the tuning described by the text `s` of a Scala `.scl` file
+///
+/// # Examples
+///
+/// ```
+/// # use synth_utils::{quantizer::Quantizer, scala};
+/// let scl = "! meantone.scl
+/// !
+/// Quarter comma meantone pentatonic, with just major thirds
+///  5
+/// !
+///  193.157
+///  5/4
+///  696.578
+///  889.735
+///  2/1
+/// ";
+///
+/// let mut q = Quantizer::new();
+/// q.set_tuning(scala::parse_scl(scl).unwrap());
+/// assert_eq!(q.convert(0.33).note_num, 2);
+/// ```
+pub fn parse_scl(scl: &str) -> Result<Tuning, ScalaError> {
+    let mut lines = Lines::new(scl);
+
+    // the first line is a description, which may even be blank, so it is skipped whatever it says
+    lines.next_description();
+
+    let (line_num, count) = lines.next_value()?;
+    let num_pitches: usize = count
+        .parse()
+        .map_err(|_| ScalaError::InvalidNumber(line_num))?;
+
+    let mut pitches = Vec::<f32, MAX_STEPS_PER_PERIOD>::new();
+    for _ in 0..num_pitches {
+        let (line_num, pitch) = lines.next_value()?;
+        pitches
+            .push(parse_pitch(pitch).ok_or(ScalaError::InvalidNumber(line_num))?)
+            .map_err(|_| ScalaError::Tuning(TuningError::TooManySteps))?;
+    }
+
+    Tuning::from_octaves(pitches.into_iter()).map_err(ScalaError::Tuning)
+}
+
+/// `parse_pitch(s)` is the Scala pitch `s` in octaves, or `None` if it is malformed
+///
+/// Pitches with a period are in cents, all others are ratios like `3/2`, or whole numbers like `2` meaning `2/1`.
+fn parse_pitch(pitch: &str) -> Option<f32> {
+    if pitch.contains('.') {
+        return pitch.parse::<f32>().ok().map(|c| c / CENTS_PER_OCTAVE);
+    }
+
+    let (num, den) = match pitch.split_once('/') {
+        Some((num, den)) => (num.parse::<u32>().ok()?, den.parse::<u32>().ok()?),
+        None => (pitch.parse::<u32>().ok()?, 1),
+    };
+
+    if num == 0 || den == 0 {
+        None
+    } else {
+        Some(ratio_to_octaves(num, den))
+    }
+}
+
+/// A Scala keyboard mapping is represented here.
+///
+/// Keyboard mappings say which MIDI notes play which degrees of a scale, and set the overall tuning by giving the
+/// frequency of a reference note.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    // the lowest and highest MIDI notes which are mapped, others are unmapped
+    first_note: u8,
+    last_note: u8,
+
+    // the MIDI note which plays the first entry of the mapping
+    middle_note: u8,
+
+    // the MIDI note which is tuned to the reference frequency
+    reference_note: u8,
+    reference_freq_hz: f32,
+
+    // the scale degree which is the formal octave, the mapping repeats at this degree
+    octave_degree: u8,
+
+    // the scale degree played by each key of the mapping pattern, `None` for unmapped keys
+    // an empty mapping means that every key plays the next scale degree
+    mapping: Vec<Option<u8>, MAX_MAPPING_SIZE>,
+}
+
+impl KeyboardMapping {
+    /// `KeyboardMapping::new()` is the standard mapping, with each key playing the next scale degree and A4 at 440Hz
+    ///
+    /// Middle C (MIDI note 60) plays the first degree of the scale.
+    pub fn new() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq_hz: 440.0_f32,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// `KeyboardMapping::parse(s)` is the keyboard mapping described by the text `s` of a Scala `.kbm` file
+    ///
+    /// Any keys missing from the end of the mapping pattern are unmapped.
+    pub fn parse(kbm: &str) -> Result<Self, ScalaError> {
+        let mut lines = Lines::new(kbm);
+
+        let map_size: usize = lines.next_number()?;
+        let first_note = lines.next_note()?;
+        let last_note = lines.next_note()?;
+        let middle_note = lines.next_note()?;
+        let reference_note = lines.next_note()?;
+
+        let (line_num, freq) = lines.next_value()?;
+        let reference_freq_hz = freq
+            .parse::<f32>()
+            .ok()
+            .filter(|f| f.is_finite() && 0.0_f32 < *f)
+            .ok_or(ScalaError::InvalidNumber(line_num))?;
+
+        let octave_degree: u8 = lines.next_number()?;
+
+        if MAX_MAPPING_SIZE < map_size {
+            return Err(ScalaError::MappingTooLarge);
+        }
+
+        let mut mapping = Vec::new();
+        for _ in 0..map_size {
+            let degree = match lines.next_value() {
+                Ok((_, "x")) | Ok((_, "X")) | Err(ScalaError::UnexpectedEnd) => None,
+                Ok((line_num, degree)) => Some(
+                    degree
+                        .parse::<u8>()
+                        .map_err(|_| ScalaError::InvalidNumber(line_num))?,
+                ),
+                Err(e) => return Err(e),
+            };
+            mapping.push(degree).ok();
+        }
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq_hz,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// `km.note_to_volts(t, n)` is the pitch of MIDI note `n` in tuning `t`, in volts with 1volt/octave scaling
+    ///
+    /// Zero volts is the pitch of MIDI note zero in standard tuning, about 8.18Hz, so in standard 12-TET tuning this is
+    /// the same as `n / 12`. This makes it easy to swap between standard and Scala tunings.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(v)` - the pitch of the note in volts
+    ///
+    /// * `None` - if the note is unmapped and should not be played
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::{mono_midi_receiver::MonoMidiReceiver, scala::KeyboardMapping, tuning::Tuning};
+    /// let mut mr = MonoMidiReceiver::new(0);
+    /// mr.parse(0x90);
+    /// mr.parse(62);
+    /// mr.parse(127);
+    ///
+    /// // quarter tones, with A4 still at 440Hz
+    /// let tuning = Tuning::equal_temperament(24);
+    /// let mapping = KeyboardMapping::new();
+    ///
+    /// let volts = mapping.note_to_volts(&tuning, mr.note_num()).unwrap();
+    /// let middle_c = mapping.note_to_volts(&tuning, 60).unwrap();
+    /// // two keys above middle C is two quarter tones, one semitone
+    /// assert!((volts - middle_c - 1.0 / 12.0).abs() < 0.001);
+    /// ```
+    pub fn note_to_volts(&self, tuning: &Tuning, note: u8) -> Option<f32> {
+        let pitch = self.pitch_above_middle(tuning, note)?;
+
+        // the reference note might be unmapped, in that case its pitch is found as if it were mapped linearly
+        let reference_pitch = self
+            .pitch_above_middle(tuning, self.reference_note)
+            .unwrap_or_else(|| tuning.pitch(self.reference_note as i32 - self.middle_note as i32));
+
+        let reference_volts = log2(self.reference_freq_hz / MIDI_NOTE_ZERO_HZ);
+
+        Some(reference_volts + pitch - reference_pitch)
+    }
+
+    /// `km.pitch_above_middle(t, n)` is the pitch of note `n` in octaves above the middle note, `None` if unmapped
+    fn pitch_above_middle(&self, tuning: &Tuning, note: u8) -> Option<f32> {
+        if note < self.first_note || self.last_note < note {
+            return None;
+        }
+
+        let keys_above_middle = note as i32 - self.middle_note as i32;
+
+        if self.mapping.is_empty() {
+            return Some(tuning.pitch(keys_above_middle));
+        }
+
+        let map_size = self.mapping.len() as i32;
+        let num_repeats = keys_above_middle.div_euclid(map_size);
+        let degree = self.mapping[keys_above_middle.rem_euclid(map_size) as usize]?;
+
+        let octave_degree = if self.octave_degree == 0 {
+            tuning.num_steps() as i32
+        } else {
+            self.octave_degree as i32
+        };
+
+        Some(num_repeats as f32 * tuning.pitch(octave_degree) + tuning.pitch(degree as i32))
+    }
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Errors which can happen when parsing Scala files are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScalaError {
+    /// The file ended before all of the expected lines were found
+    UnexpectedEnd,
+    /// The value on the given line number (starting at 1) could not be read
+    InvalidNumber(usize),
+    /// The keyboard mapping has more than `MAX_MAPPING_SIZE` keys
+    MappingTooLarge,
+    /// The pitches could be read, but they don't make a valid tuning
+    Tuning(TuningError),
+}
+
+/// An iterator-like helper over the meaningful lines of a Scala file
+struct Lines<'a> {
+    lines: core::iter::Enumerate<core::str::Lines<'a>>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            lines: text.lines().enumerate(),
+        }
+    }
+
+    /// `l.next_description()` skips past the next line which is not a comment, even if it is blank
+    fn next_description(&mut self) {
+        for (_, line) in self.lines.by_ref() {
+            if !line.starts_with('!') {
+                return;
+            }
+        }
+    }
+
+    /// `l.next_value()` is the line number and first word of the next line which is not a comment or blank
+    ///
+    /// Anything after the first word is ignored, as Scala allows for labels after values.
+    fn next_value(&mut self) -> Result<(usize, &'a str), ScalaError> {
+        for (i, line) in self.lines.by_ref() {
+            if line.starts_with('!') {
+                continue;
+            }
+            if let Some(word) = line.split_whitespace().next() {
+                return Ok((i + 1, word));
+            }
+        }
+        Err(ScalaError::UnexpectedEnd)
+    }
+
+    /// `l.next_number()` is the next value parsed as a number
+    fn next_number<T: core::str::FromStr>(&mut self) -> Result<T, ScalaError> {
+        let (line_num, value) = self.next_value()?;
+        value
+            .parse::<T>()
+            .map_err(|_| ScalaError::InvalidNumber(line_num))
+    }
+
+    /// `l.next_note()` is the next value parsed as a MIDI note number in `[0..127]`
+    fn next_note(&mut self) -> Result<u8, ScalaError> {
+        let (line_num, value) = self.next_value()?;
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|n| *n <= 127)
+            .ok_or(ScalaError::InvalidNumber(line_num))
+    }
+}
+
+/// The maximum number of keys in a keyboard mapping pattern
+pub const MAX_MAPPING_SIZE: usize = 128;
+
+/// The frequency of MIDI note zero in standard tuning, with A4 (MIDI note 69) at 440Hz
+const MIDI_NOTE_ZERO_HZ: f32 = 8.175_799_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWELVE_TET_SCL: &str = "! 12tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    #[test]
+    fn parses_cents_and_ratios() {
+        let t = parse_scl(TWELVE_TET_SCL).unwrap();
+        let et = Tuning::equal_temperament(12);
+        assert_eq!(t.num_steps(), 12);
+        for step in 0..24 {
+            assert!(is_almost(t.pitch(step), et.pitch(step), 0.000_01));
+        }
+    }
+
+    #[test]
+    fn whole_number_pitch_is_a_ratio() {
+        let t = parse_scl("tritave\n1\n3\n").unwrap();
+        assert!(is_almost(t.period(), 1.584_962_5, 0.000_001));
+    }
+
+    #[test]
+    fn labels_after_pitches_are_ignored() {
+        let t = parse_scl("fifths\n2\n3/2 perfect fifth\n2/1 octave\n").unwrap();
+        assert!(is_almost(t.pitch(1), 0.584_962_5, 0.000_001));
+    }
+
+    #[test]
+    fn blank_description_is_allowed() {
+        let t = parse_scl("!comment\n\n1\n2/1\n").unwrap();
+        assert_eq!(t.num_steps(), 1);
+    }
+
+    #[test]
+    fn malformed_scl_files_are_errors() {
+        assert_eq!(parse_scl(""), Err(ScalaError::UnexpectedEnd));
+        assert_eq!(
+            parse_scl("desc\n3\n3/2\n2/1\n"),
+            Err(ScalaError::UnexpectedEnd)
+        );
+        assert_eq!(
+            parse_scl("desc\nthree\n"),
+            Err(ScalaError::InvalidNumber(2))
+        );
+        assert_eq!(
+            parse_scl("desc\n2\n3/0\n2/1\n"),
+            Err(ScalaError::InvalidNumber(3))
+        );
+        assert_eq!(
+            parse_scl("desc\n2\nabc\n2/1\n"),
+            Err(ScalaError::InvalidNumber(3))
+        );
+        assert_eq!(
+            parse_scl("desc\n2\n2/1\n3/2\n"),
+            Err(ScalaError::Tuning(TuningError::NotAscending))
+        );
+        assert_eq!(
+            parse_scl("desc\n0\n"),
+            Err(ScalaError::Tuning(TuningError::Empty))
+        );
+    }
+
+    #[test]
+    fn standard_mapping_matches_note_num_over_12() {
+        let t = Tuning::equal_temperament(12);
+        let km = KeyboardMapping::new();
+        for note in 0..=127 {
+            let volts = km.note_to_volts(&t, note).unwrap();
+            assert!(is_almost(volts, note as f32 / 12.0, 0.000_1));
+        }
+    }
+
+    #[test]
+    fn parses_kbm_with_unmapped_keys() {
+        let kbm = "! white keys only
+7
+0
+127
+60
+69
+440.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+";
+        // a 7 key pattern where some of the keys are unmapped
+        let km = KeyboardMapping::parse(kbm).unwrap();
+        let t = Tuning::equal_temperament(7);
+
+        assert!(km.note_to_volts(&t, 60).is_some());
+        assert!(km.note_to_volts(&t, 61).is_none());
+
+        // the pattern repeats every 7 keys, one formal octave higher
+        let c = km.note_to_volts(&t, 60).unwrap();
+        let c_up = km.note_to_volts(&t, 67).unwrap();
+        assert!(is_almost(c_up - c, 1.0, 0.000_1));
+    }
+
+    #[test]
+    fn missing_mapping_entries_are_unmapped() {
+        let km = KeyboardMapping::parse("2\n0\n127\n60\n69\n440.0\n1\n0\n").unwrap();
+        let t = Tuning::equal_temperament(1);
+        assert!(km.note_to_volts(&t, 60).is_some());
+        assert!(km.note_to_volts(&t, 61).is_none());
+    }
+
+    #[test]
+    fn notes_outside_the_range_are_unmapped() {
+        let km = KeyboardMapping::parse("0\n36\n84\n60\n69\n440.0\n0\n").unwrap();
+        let t = Tuning::equal_temperament(12);
+        assert!(km.note_to_volts(&t, 35).is_none());
+        assert!(km.note_to_volts(&t, 36).is_some());
+        assert!(km.note_to_volts(&t, 84).is_some());
+        assert!(km.note_to_volts(&t, 85).is_none());
+    }
+
+    #[test]
+    fn reference_frequency_shifts_the_pitch() {
+        // A4 at 220Hz is one octave lower than usual
+        let km = KeyboardMapping::parse("0\n0\n127\n60\n69\n220.0\n0\n").unwrap();
+        let t = Tuning::equal_temperament(12);
+        assert!(is_almost(
+            km.note_to_volts(&t, 69).unwrap(),
+            69.0 / 12.0 - 1.0,
+            0.000_1
+        ));
+    }
+
+    #[test]
+    fn malformed_kbm_files_are_errors() {
+        assert_eq!(KeyboardMapping::parse(""), Err(ScalaError::UnexpectedEnd));
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n128\n60\n69\n440.0\n0\n"),
+            Err(ScalaError::InvalidNumber(3))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n127\n60\n69\n-440.0\n0\n"),
+            Err(ScalaError::InvalidNumber(6))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n127\n60\n69\ninf\n0\n"),
+            Err(ScalaError::InvalidNumber(6))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n127\n60\n69\nNaN\n0\n"),
+            Err(ScalaError::InvalidNumber(6))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("1\n0\n127\n60\n69\n440.0\n0\nq\n"),
+            Err(ScalaError::InvalidNumber(8))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("200\n0\n127\n60\n69\n440.0\n0\n"),
+            Err(ScalaError::MappingTooLarge)
+        );
+    }
+}
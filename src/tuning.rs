@@ -0,0 +1,304 @@
+//! # Tuning
+//!
+//! A tuning describes the pitches of the steps which make up a scale, and the interval at which those steps repeat.
+//!
+//! Most western music uses 12 tone equal temperament (12-TET), where the octave is divided into 12 equal steps. Other
+//! equal divisions of the octave (N-EDO) such as 19, 24, or 31 steps are popular with microtonal musicians, and just
+//! intonation tunings use steps built from whole number frequency ratios.
+//!
+//! Pitches within a tuning are measured in octaves, which is the same as volts with 1volt/octave scaling. Step zero is
+//! always at zero octaves, and the steps repeat every period. The period is usually one octave, but some tunings repeat
+//! at other intervals, for example the Bohlen-Pierce scale repeats at a perfect twelfth.
+
+use heapless::Vec;
+
+use crate::utils::*;
+
+/// A tuning with up to `MAX_STEPS_PER_PERIOD` steps per period is represented here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    // the pitch of each step above step zero in octaves, ascending, the first step is always zero
+    steps: Vec<f32, MAX_STEPS_PER_PERIOD>,
+
+    // the interval at which the steps repeat, in octaves
+    period: f32,
+}
+
+impl Tuning {
+    /// `Tuning::equal_temperament(n)` is a tuning which divides the octave into `n` equal steps
+    ///
+    /// The number of steps is clamped to `[1, MAX_STEPS_PER_PERIOD]`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::tuning::Tuning;
+    /// let t = Tuning::equal_temperament(24);
+    /// // quarter tones
+    /// assert_eq!(t.pitch(1), 1.0 / 24.0);
+    /// ```
+    pub fn equal_temperament(num_steps: usize) -> Self {
+        let num_steps = num_steps.clamp(1, MAX_STEPS_PER_PERIOD);
+
+        Self {
+            steps: (0..num_steps)
+                .map(|n| n as f32 / num_steps as f32)
+                .collect(),
+            period: 1.0_f32,
+        }
+    }
+
+    /// `Tuning::from_cents(cs)` is a tuning built from the pitches `cs` in cents
+    ///
+    /// As in Scala scale files, the pitches are for the steps above step zero, and the last pitch is the period. So the
+    /// number of steps is the same as the number of pitches. There are 1200 cents in an octave.
+    ///
+    /// # Errors
+    ///
+    /// The pitches must be finite, positive, and strictly ascending, and there must be between 1 and
+    /// `MAX_STEPS_PER_PERIOD` of them. The period must be at least `MIN_PERIOD_CENTS`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::tuning::Tuning;
+    /// // 5 equal steps per octave
+    /// let t = Tuning::from_cents(&[240.0, 480.0, 720.0, 960.0, 1200.0]).unwrap();
+    /// assert_eq!(t.num_steps(), 5);
+    /// assert_eq!(t.period(), 1.0);
+    /// ```
+    pub fn from_cents(cents: &[f32]) -> Result<Self, TuningError> {
+        Self::from_octaves(cents.iter().map(|c| c / CENTS_PER_OCTAVE))
+    }
+
+    /// `Tuning::from_ratios(rs)` is a tuning built from the frequency ratios `rs`, given as `(numerator, denominator)`
+    ///
+    /// As in Scala scale files, the ratios are for the steps above step zero, and the last ratio is the period.
+    ///
+    /// # Errors
+    ///
+    /// The ratios must be greater than 1 and strictly ascending, and there must be between 1 and
+    /// `MAX_STEPS_PER_PERIOD` of them. Ratios with a zero numerator or denominator are not allowed. The period must be at
+    /// least `MIN_PERIOD_CENTS`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use synth_utils::tuning::Tuning;
+    /// // just intonation major pentatonic
+    /// let t = Tuning::from_ratios(&[(9, 8), (5, 4), (3, 2), (5, 3), (2, 1)]).unwrap();
+    /// assert_eq!(t.num_steps(), 5);
+    /// ```
+    pub fn from_ratios(ratios: &[(u32, u32)]) -> Result<Self, TuningError> {
+        if ratios.iter().any(|(num, den)| *num == 0 || *den == 0) {
+            return Err(TuningError::InvalidRatio);
+        }
+        Self::from_octaves(ratios.iter().map(|(num, den)| ratio_to_octaves(*num, *den)))
+    }
+
+    /// `Tuning::from_octaves(ps)` is a tuning built from the pitches `ps` in octaves, the last pitch is the period
+    pub(crate) fn from_octaves<I: Iterator<Item = f32>>(pitches: I) -> Result<Self, TuningError> {
+        let mut steps = Vec::new();
+        steps.push(0.0_f32).ok();
+
+        // each pitch is held back until we know it isn't the last one, the last pitch is the period
+        let mut period = None;
+
+        for p in pitches {
+            if !p.is_finite() {
+                return Err(TuningError::NotFinite);
+            }
+            let highest_so_far = period.unwrap_or(0.0_f32);
+            if p <= highest_so_far {
+                return Err(TuningError::NotAscending);
+            }
+            if let Some(prev) = period.replace(p) {
+                steps.push(prev).map_err(|_| TuningError::TooManySteps)?;
+            }
+        }
+
+        match period {
+            // a tiny period would make every pitch span a huge number of steps
+            Some(period) if period < MIN_PERIOD_CENTS / CENTS_PER_OCTAVE => {
+                Err(TuningError::PeriodTooSmall)
+            }
+            Some(period) => Ok(Self { steps, period }),
+            None => Err(TuningError::Empty),
+        }
+    }
+
+    /// `t.num_steps()` is the number of steps in each period
+    pub fn num_steps(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// `t.period()` is the interval at which the steps repeat, in octaves
+    pub fn period(&self) -> f32 {
+        self.period
+    }
+
+    /// `t.pitch(s)` is the pitch of step number `s` above step zero, in octaves
+    ///
+    /// Step numbers keep counting up past the end of the period, so in 12-TET step 12 is one octave above step zero.
+    pub fn pitch(&self, step: i32) -> f32 {
+        let n = self.steps.len() as i32;
+        step.div_euclid(n) as f32 * self.period + self.steps[step.rem_euclid(n) as usize]
+    }
+
+    /// `t.step_below(p)` is the highest step number with a pitch not higher than pitch `p` in octaves
+    ///
+    /// A small tolerance is allowed so that pitches which are equal to a step except for rounding errors count as that
+    /// step.
+    pub(crate) fn step_below(&self, pitch: f32) -> i32 {
+        let pitch = pitch + PITCH_TOLERANCE;
+
+        let period_num = floor(pitch / self.period);
+        let pitch_in_period = pitch - period_num as f32 * self.period;
+
+        let step_in_period = self
+            .steps
+            .iter()
+            .rposition(|s| *s <= pitch_in_period)
+            .unwrap_or(0);
+
+        period_num * self.steps.len() as i32 + step_in_period as i32
+    }
+}
+
+/// Errors which can happen when building a tuning are represented here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TuningError {
+    /// There were no pitches given
+    Empty,
+    /// There were more than `MAX_STEPS_PER_PERIOD` pitches given
+    TooManySteps,
+    /// The pitches were not all above the unison and strictly ascending
+    NotAscending,
+    /// A pitch was infinite or not a number
+    NotFinite,
+    /// A ratio had a zero numerator or denominator
+    InvalidRatio,
+    /// The period was smaller than `MIN_PERIOD_CENTS`
+    PeriodTooSmall,
+}
+
+/// `ratio_to_octaves(n, d)` is the frequency ratio `n/d` converted to octaves
+pub(crate) fn ratio_to_octaves(num: u32, den: u32) -> f32 {
+    log2(num as f32 / den as f32)
+}
+
+/// The maximum number of steps a tuning may have in each period
+pub const MAX_STEPS_PER_PERIOD: usize = 64;
+
+/// The smallest period a tuning may have, in cents
+pub const MIN_PERIOD_CENTS: f32 = 1.0_f32;
+
+/// The number of cents in one octave
+pub const CENTS_PER_OCTAVE: f32 = 1200.0_f32;
+
+/// Pitches closer than this to a step, in octaves, count as being on the step, 10 microvolts at 1volt/octave
+pub(crate) const PITCH_TOLERANCE: f32 = 0.000_01_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twelve_tet_has_semitone_steps() {
+        let t = Tuning::equal_temperament(12);
+        assert_eq!(t.num_steps(), 12);
+        assert_eq!(t.period(), 1.0);
+        assert!(is_almost(t.pitch(7), 7.0 / 12.0, 0.000_001));
+        assert!(is_almost(t.pitch(25), 2.0 + 1.0 / 12.0, 0.000_001));
+    }
+
+    #[test]
+    fn equal_temperament_is_clamped() {
+        assert_eq!(Tuning::equal_temperament(0).num_steps(), 1);
+        assert_eq!(
+            Tuning::equal_temperament(1_000).num_steps(),
+            MAX_STEPS_PER_PERIOD
+        );
+    }
+
+    #[test]
+    fn negative_steps_go_below_zero() {
+        let t = Tuning::equal_temperament(12);
+        assert!(is_almost(t.pitch(-1), -1.0 / 12.0, 0.000_001));
+        assert!(is_almost(t.pitch(-12), -1.0, 0.000_001));
+    }
+
+    #[test]
+    fn cents_tuning_matches_equal_temperament() {
+        let t = Tuning::from_cents(&[
+            100.0, 200.0, 300.0, 400.0, 500.0, 600.0, 700.0, 800.0, 900.0, 1000.0, 1100.0, 1200.0,
+        ])
+        .unwrap();
+        let et = Tuning::equal_temperament(12);
+        for step in -20..40 {
+            assert!(is_almost(t.pitch(step), et.pitch(step), 0.000_01));
+        }
+    }
+
+    #[test]
+    fn just_fifth_is_a_bit_wider_than_tempered() {
+        let t = Tuning::from_ratios(&[(3, 2), (2, 1)]).unwrap();
+        // a just fifth is about 701.955 cents
+        assert!(is_almost(t.pitch(1) * CENTS_PER_OCTAVE, 701.955, 0.001));
+        assert!(is_almost(t.pitch(2), 1.0, 0.000_001));
+    }
+
+    #[test]
+    fn non_octave_period() {
+        // Bohlen-Pierce repeats at the tritave, 3:1
+        let t = Tuning::from_ratios(&[(3, 1)]).unwrap();
+        assert!(is_almost(t.period(), 1.584_963, 0.000_01));
+        assert!(is_almost(t.pitch(2), 2.0 * 1.584_963, 0.000_1));
+    }
+
+    #[test]
+    fn bad_pitches_are_errors() {
+        assert_eq!(Tuning::from_cents(&[]), Err(TuningError::Empty));
+        assert_eq!(
+            Tuning::from_cents(&[200.0, 100.0]),
+            Err(TuningError::NotAscending)
+        );
+        assert_eq!(
+            Tuning::from_cents(&[-100.0, 1200.0]),
+            Err(TuningError::NotAscending)
+        );
+        assert_eq!(
+            Tuning::from_ratios(&[(1, 0)]),
+            Err(TuningError::InvalidRatio)
+        );
+        assert_eq!(
+            Tuning::from_ratios(&[(3, 2), (0, 1)]),
+            Err(TuningError::InvalidRatio)
+        );
+        assert_eq!(Tuning::from_cents(&[0.5]), Err(TuningError::PeriodTooSmall));
+        assert_eq!(
+            Tuning::from_ratios(&[(1_000_001, 1_000_000)]),
+            Err(TuningError::PeriodTooSmall)
+        );
+        assert!(Tuning::from_cents(&[MIN_PERIOD_CENTS]).is_ok());
+        assert_eq!(Tuning::from_cents(&[f32::NAN]), Err(TuningError::NotFinite));
+        assert_eq!(
+            Tuning::from_cents(&[100.0, f32::INFINITY]),
+            Err(TuningError::NotFinite)
+        );
+        let too_many: [f32; 65] = core::array::from_fn(|i| (i + 1) as f32);
+        assert_eq!(
+            Tuning::from_cents(&too_many),
+            Err(TuningError::TooManySteps)
+        );
+    }
+
+    #[test]
+    fn step_below_finds_the_containing_step() {
+        let t = Tuning::equal_temperament(12);
+        assert_eq!(t.step_below(0.0), 0);
+        assert_eq!(t.step_below(1.5 / 12.0), 1);
+        assert_eq!(t.step_below(2.0 + 1.0 / 12.0), 25);
+        assert_eq!(t.step_below(-0.5 / 12.0), -1);
+    }
+}
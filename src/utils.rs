@@ -37,6 +37,44 @@ pub fn fabs(v: f32) -> f32 {
     }
 }
 
+/// `floor(v)` is `v` rounded down to the nearest integer
+///
+/// Values outside of the range of `i32` are undefined
+pub fn floor(v: f32) -> i32 {
+    let truncated = v as i32;
+    if v < truncated as f32 {
+        truncated - 1
+    } else {
+        truncated
+    }
+}
+
+/// `log2(x)` is the base 2 logarithm of `x`
+///
+/// `x` must be positive and finite, other values are undefined.
+///
+/// The input is split into a power of two and a mantissa in `[1.0, 2.0)`, and the natural log of the mantissa is found
+/// with a fast converging series. This is accurate to well under a thousandth of a cent when used for musical pitch.
+pub fn log2(x: f32) -> f32 {
+    let x = x as f64;
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & 0x000F_FFFF_FFFF_FFFF) | 0x3FF0_0000_0000_0000);
+
+    // ln(m) = 2 * (z + z^3/3 + z^5/5 + ...) with z = (m - 1) / (m + 1), z is at most 1/3 so this converges quickly
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z_squared = z * z;
+    let mut term = z;
+    let mut ln_mantissa = 0.0_f64;
+    for n in 0..12 {
+        ln_mantissa += term / (2 * n + 1) as f64;
+        term *= z_squared;
+    }
+    ln_mantissa *= 2.0;
+
+    (exponent as f64 + ln_mantissa / core::f64::consts::LN_2) as f32
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,4 +119,28 @@ mod tests {
     fn ilog_2_of_1024_is_10() {
         assert_eq!(ilog_2(1024), 10);
     }
+
+    #[test]
+    fn floor_rounds_down() {
+        assert_eq!(floor(1.5), 1);
+        assert_eq!(floor(2.0), 2);
+        assert_eq!(floor(-0.5), -1);
+        assert_eq!(floor(-2.0), -2);
+    }
+
+    #[test]
+    fn log2_of_powers_of_2() {
+        assert_eq!(log2(1.0), 0.0);
+        assert_eq!(log2(2.0), 1.0);
+        assert_eq!(log2(1024.0), 10.0);
+        assert_eq!(log2(0.25), -2.0);
+    }
+
+    #[test]
+    fn log2_of_other_numbers() {
+        assert!(is_almost(log2(3.0), 1.584_962_5, 0.000_001));
+        assert!(is_almost(log2(1.5), 0.584_962_5, 0.000_001));
+        assert!(is_almost(log2(10.0), core::f32::consts::LOG2_10, 0.000_001));
+        assert!(is_almost(log2(0.1), -core::f32::consts::LOG2_10, 0.000_001));
+    }
 }